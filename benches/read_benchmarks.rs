@@ -1,6 +1,6 @@
 use criterion::{Criterion, criterion_group, criterion_main};
 use my_fuse::test_util::TestFixture;
-use std::{fs, hint::black_box};
+use std::{fs, hint::black_box, os::unix::fs::FileExt};
 
 fn bench_read_file(c: &mut Criterion) {
     let fixture = TestFixture::new();
@@ -119,6 +119,52 @@ fn bench_read_with_dir_listing(c: &mut Criterion) {
     });
 }
 
+fn bench_random_writes(c: &mut Criterion) {
+    // Small writes into a large file should not depend on the size of the file
+    let fixture = TestFixture::new();
+    let file_path = fixture.path().join("random_writes");
+    let file_size = 64 * 1024 * 1024;
+    let file = fs::File::create(&file_path).unwrap();
+    file.set_len(file_size).unwrap();
+    let mut offset = 0u64;
+
+    c.bench_function("random_writes", |b| {
+        b.iter(|| {
+            // A simple linear congruential generator spreads the writes over the whole file
+            offset = (offset.wrapping_mul(6364136223846793005).wrapping_add(1)) % file_size;
+            file.write_all_at(b"random write", offset).unwrap();
+        })
+    });
+}
+
+fn bench_sparse_write(c: &mut Criterion) {
+    // Writing far behind the end of a file should leave a hole instead of filling it
+    let fixture = TestFixture::new();
+    let file_path = fixture.path().join("sparse_write");
+
+    c.bench_function("sparse_write", |b| {
+        b.iter(|| {
+            let file = fs::File::create(&file_path).unwrap();
+            file.write_all_at(b"end", 1024 * 1024 * 1024).unwrap();
+            black_box(file);
+        })
+    });
+}
+
+fn bench_read_sparse_file(c: &mut Criterion) {
+    let fixture = TestFixture::new();
+    let file_path = fixture.path().join("sparse_read");
+    let file = fs::File::create(&file_path).unwrap();
+    file.set_len(1024 * 1024).unwrap();
+
+    c.bench_function("read_sparse_file", |b| {
+        b.iter(|| {
+            let data = fs::read(&file_path).unwrap();
+            black_box(data);
+        })
+    });
+}
+
 criterion_group!(
     benches,
     bench_read_file,
@@ -126,7 +172,10 @@ criterion_group!(
     bench_read_multiple_files,
     bench_read_different_sizes,
     bench_concurrent_reads,
-    bench_read_with_dir_listing
+    bench_read_with_dir_listing,
+    bench_random_writes,
+    bench_sparse_write,
+    bench_read_sparse_file
 );
 
 criterion_main!(benches);
@@ -0,0 +1,46 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use my_fuse::test_util::TestFixture;
+use std::{fs, hint::black_box};
+
+const ENTRY_COUNT: usize = 100_000;
+
+/// Creates a folder with `ENTRY_COUNT` empty files in the mounted filesystem
+fn large_folder(fixture: &TestFixture) -> std::path::PathBuf {
+    let folder = fixture.path().join("large");
+    fs::create_dir(&folder).unwrap();
+    for i in 0..ENTRY_COUNT {
+        fs::File::create(folder.join(format!("file_{i}"))).unwrap();
+    }
+    folder
+}
+
+fn bench_list_large_folder(c: &mut Criterion) {
+    // Like `ls`, only the names are needed
+    let fixture = TestFixture::new();
+    let folder = large_folder(&fixture);
+
+    let mut group = c.benchmark_group("list_100k_entries");
+    group.sample_size(10);
+    group.bench_function("ls", |b| {
+        b.iter(|| {
+            let count = fs::read_dir(&folder).unwrap().count();
+            black_box(count);
+        })
+    });
+
+    // Like `ls -l`, every entry is also stat'ed. With readdirplus the listing already brings the attributes
+    group.bench_function("ls_l", |b| {
+        b.iter(|| {
+            let sizes: u64 = fs::read_dir(&folder)
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum();
+            black_box(sizes);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_list_large_folder);
+
+criterion_main!(benches);
@@ -0,0 +1,109 @@
+use std::{ffi::OsString, io};
+
+use log::debug;
+
+use crate::{Handle, Inode};
+
+/// The ways a request to the filesystem can fail.
+/// The kernel only gets to see the errno of an error, the rest of it ends up in the log.
+#[derive(Debug)]
+pub enum Error {
+    /// There is no node with this inode (ENOENT)
+    InodeNotFound(Inode),
+
+    /// The folder has no entry with this name (ENOENT)
+    EntryNotFound(Inode, OsString),
+
+    /// The node is not a folder, but the request needs one (ENOTDIR)
+    NotADirectory(Inode),
+
+    /// The node is a folder, but the request does not work on folders (EISDIR)
+    IsADirectory(Inode),
+
+    /// The folder already has an entry with this name (EEXIST)
+    AlreadyExists(Inode, OsString),
+
+    /// The name is longer than NAME_MAX bytes (ENAMETOOLONG)
+    NameTooLong,
+
+    /// The folder still has entries, so it can not be removed or replaced (ENOTEMPTY)
+    NotEmpty(Inode),
+
+    /// The file would grow beyond the largest supported size (EFBIG)
+    FileTooLarge,
+
+    /// The byte or inode budget is used up (ENOSPC)
+    NoSpace,
+
+    /// An argument of the request is invalid for this node (EINVAL)
+    InvalidArgument,
+
+    /// The request is not supported with these arguments (EOPNOTSUPP)
+    NotSupported,
+
+    /// The request is never allowed for this node, like a hard link to a folder (EPERM)
+    NotPermitted(Inode),
+
+    /// The request only works on regular files (ENODEV)
+    NotAFile(Inode),
+
+    /// The handle was never handed out or is already released (EBADF)
+    BadHandle(Handle),
+
+    /// The node has no extended attribute with this name (ENODATA)
+    NoAttribute(Inode),
+
+    /// The reply does not fit into the buffer of the caller or a name is too long (ERANGE)
+    OutOfRange,
+
+    /// The value is larger than the filesystem supports (E2BIG)
+    TooBig,
+
+    /// There is no data or hole at or after the offset (ENXIO)
+    NoSuchOffset(u64),
+
+    /// Writing the journal failed
+    Io(io::Error),
+}
+
+impl Error {
+    /// The errno the kernel reports for this error
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::InodeNotFound(_) | Error::EntryNotFound(..) => libc::ENOENT,
+            Error::NotADirectory(_) => libc::ENOTDIR,
+            Error::IsADirectory(_) => libc::EISDIR,
+            Error::AlreadyExists(..) => libc::EEXIST,
+            Error::NameTooLong => libc::ENAMETOOLONG,
+            Error::NotEmpty(_) => libc::ENOTEMPTY,
+            Error::FileTooLarge => libc::EFBIG,
+            Error::NoSpace => libc::ENOSPC,
+            Error::InvalidArgument => libc::EINVAL,
+            Error::NotSupported => libc::EOPNOTSUPP,
+            Error::NotPermitted(_) => libc::EPERM,
+            Error::NotAFile(_) => libc::ENODEV,
+            Error::BadHandle(_) => libc::EBADF,
+            Error::NoAttribute(_) => libc::ENODATA,
+            Error::OutOfRange => libc::ERANGE,
+            Error::TooBig => libc::E2BIG,
+            Error::NoSuchOffset(_) => libc::ENXIO,
+            Error::Io(error) => error.raw_os_error().unwrap_or(libc::EIO),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        debug!("Request failed with {error:?}");
+        match error {
+            Error::Io(error) => error,
+            error => io::Error::from_raw_os_error(error.errno()),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::{collections::BTreeMap, io};
+
+/// The size of one chunk of file content. Chunks are the unit in which space is allocated.
+pub const CHUNK_SIZE: usize = 4096;
+
+/// A chunk that was never written reads back as zeros
+static ZERO_CHUNK: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+
+/// The content of a file stored in fixed size chunks.
+/// Only chunks that were written take up memory, everything else is a hole that reads as zeros.
+/// This keeps writes and truncates proportional to the touched range instead of the file size.
+#[derive(Debug, Default)]
+pub struct FileData {
+    /// This BTree maps the index of a chunk (offset / CHUNK_SIZE) to its content
+    chunks: BTreeMap<u64, Box<[u8; CHUNK_SIZE]>>,
+
+    /// The size of the file in bytes. The last chunk may reach beyond it, but is zero there.
+    size: u64,
+}
+
+impl FileData {
+    /// The size of the file in bytes including holes
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// The number of bytes that are actually allocated for the content
+    pub fn allocated(&self) -> u64 {
+        (self.chunks.len() * CHUNK_SIZE) as u64
+    }
+
+    /// All allocated chunks with the offset they start at
+    pub fn chunks(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.chunks
+            .iter()
+            .map(|(index, chunk)| (index * CHUNK_SIZE as u64, &chunk[..]))
+    }
+
+    /// The number of bytes that writing `len` bytes at `offset` would newly allocate
+    pub fn allocation_needed(&self, offset: u64, len: u64) -> u64 {
+        chunk_range(offset, len)
+            .filter(|index| !self.chunks.contains_key(index))
+            .count() as u64
+            * CHUNK_SIZE as u64
+    }
+
+    /// Writes up to `size` bytes starting at `offset` into `w` and returns how many were written.
+    /// Reading stops at the end of the file.
+    pub fn read(&self, offset: u64, size: usize, w: &mut dyn io::Write) -> io::Result<usize> {
+        let end = offset.saturating_add(size as u64).min(self.size);
+        let mut position = offset;
+        while position < end {
+            let index = position / CHUNK_SIZE as u64;
+            let start = (position % CHUNK_SIZE as u64) as usize;
+            let len = (CHUNK_SIZE - start).min((end - position) as usize);
+            let chunk = self
+                .chunks
+                .get(&index)
+                .map_or(&ZERO_CHUNK, |chunk| &**chunk);
+            w.write_all(&chunk[start..start + len])?;
+            position += len as u64;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    /// Writes `buf` at `offset`. Writing beyond the end of the file leaves a hole in between.
+    pub fn write(&mut self, offset: u64, buf: &[u8]) {
+        let mut written = 0;
+        while written < buf.len() {
+            let position = offset + written as u64;
+            let index = position / CHUNK_SIZE as u64;
+            let start = (position % CHUNK_SIZE as u64) as usize;
+            let len = (CHUNK_SIZE - start).min(buf.len() - written);
+            let chunk = self
+                .chunks
+                .entry(index)
+                .or_insert_with(|| Box::new([0; CHUNK_SIZE]));
+            chunk[start..start + len].copy_from_slice(&buf[written..written + len]);
+            written += len;
+        }
+        self.size = self.size.max(offset + buf.len() as u64);
+    }
+
+    /// Changes the size of the file. Growing adds a hole, shrinking frees the chunks past the end.
+    pub fn truncate(&mut self, size: u64) {
+        if size < self.size {
+            let first_free = size.div_ceil(CHUNK_SIZE as u64);
+            self.chunks.split_off(&first_free);
+            // The rest of the last chunk has to read as zeros if the file grows again
+            let start = (size % CHUNK_SIZE as u64) as usize;
+            if start != 0
+                && let Some(chunk) = self.chunks.get_mut(&(size / CHUNK_SIZE as u64))
+            {
+                chunk[start..].fill(0);
+            }
+        }
+        self.size = size;
+    }
+
+    /// Allocates all chunks in the range, so writing to it later can not run out of space
+    pub fn allocate(&mut self, offset: u64, len: u64) {
+        for index in chunk_range(offset, len) {
+            self.chunks
+                .entry(index)
+                .or_insert_with(|| Box::new([0; CHUNK_SIZE]));
+        }
+    }
+
+    /// Zeroes the range. Chunks that are completely inside of it are freed.
+    pub fn punch_hole(&mut self, offset: u64, len: u64) {
+        let end = offset.saturating_add(len);
+        for index in chunk_range(offset, len) {
+            let chunk_start = index * CHUNK_SIZE as u64;
+            let chunk_end = chunk_start + CHUNK_SIZE as u64;
+            if offset <= chunk_start && chunk_end <= end {
+                self.chunks.remove(&index);
+            } else if let Some(chunk) = self.chunks.get_mut(&index) {
+                let start = offset.max(chunk_start) - chunk_start;
+                let stop = end.min(chunk_end) - chunk_start;
+                chunk[start as usize..stop as usize].fill(0);
+            }
+        }
+    }
+
+    /// Zeroes the range and keeps it allocated
+    pub fn zero_range(&mut self, offset: u64, len: u64) {
+        self.punch_hole(offset, len);
+        self.allocate(offset, len);
+    }
+
+    /// The first offset at or after `offset` that lies in allocated data.
+    /// Returns None if there is only a hole left until the end of the file.
+    pub fn seek_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let (&index, _) = self.chunks.range(offset / CHUNK_SIZE as u64..).next()?;
+        let position = offset.max(index * CHUNK_SIZE as u64);
+        (position < self.size).then_some(position)
+    }
+
+    /// The first offset at or after `offset` that lies in a hole.
+    /// The end of the file counts as a hole. Returns None if `offset` is past the end of the file.
+    pub fn seek_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let mut index = offset / CHUNK_SIZE as u64;
+        while self.chunks.contains_key(&index) {
+            index += 1;
+        }
+        Some(offset.max(index * CHUNK_SIZE as u64).min(self.size))
+    }
+}
+
+/// The indices of all chunks touched by `len` bytes at `offset`
+fn chunk_range(offset: u64, len: u64) -> std::ops::Range<u64> {
+    if len == 0 {
+        return 0..0;
+    }
+    let first = offset / CHUNK_SIZE as u64;
+    let last = (offset + len - 1) / CHUNK_SIZE as u64;
+    first..last + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CHUNK_SIZE, FileData};
+
+    fn read_all(data: &FileData) -> Vec<u8> {
+        let mut buf = Vec::new();
+        data.read(0, data.len() as usize, &mut buf).unwrap();
+        buf
+    }
+
+    #[test_log::test]
+    fn write_across_chunks() {
+        // Arrange
+        let mut data = FileData::default();
+
+        // Act
+
+        data.write(CHUNK_SIZE as u64 - 2, b"test");
+
+        // Assert
+
+        assert_eq!(data.len(), CHUNK_SIZE as u64 + 2);
+        assert_eq!(data.allocated(), 2 * CHUNK_SIZE as u64);
+        let mut buf = Vec::new();
+        data.read(CHUNK_SIZE as u64 - 2, 100, &mut buf).unwrap();
+        assert_eq!(buf, b"test");
+    }
+
+    #[test_log::test]
+    fn write_past_end_leaves_hole() {
+        // Arrange
+        let mut data = FileData::default();
+        data.write(0, b"start");
+
+        // Act
+
+        data.write(10 * CHUNK_SIZE as u64, b"end");
+
+        // Assert
+
+        assert_eq!(data.len(), 10 * CHUNK_SIZE as u64 + 3);
+        assert_eq!(data.allocated(), 2 * CHUNK_SIZE as u64);
+        let content = read_all(&data);
+        assert_eq!(&content[..5], b"start");
+        assert!(content[5..10 * CHUNK_SIZE].iter().all(|&b| b == 0));
+        assert_eq!(&content[10 * CHUNK_SIZE..], b"end");
+    }
+
+    #[test_log::test]
+    fn truncate_frees_chunks_and_zeroes_tail() {
+        // Arrange
+        let mut data = FileData::default();
+        data.write(0, &[1; 3 * CHUNK_SIZE]);
+
+        // Act
+
+        data.truncate(10);
+        data.truncate(20);
+
+        // Assert
+
+        assert_eq!(data.allocated(), CHUNK_SIZE as u64);
+        let content = read_all(&data);
+        assert_eq!(&content[..10], &[1; 10]);
+        assert_eq!(&content[10..], &[0; 10]);
+    }
+
+    #[test_log::test]
+    fn allocation_needed() {
+        // Arrange
+        let mut data = FileData::default();
+
+        // Act
+
+        data.write(0, b"test");
+
+        // Assert
+
+        assert_eq!(data.allocation_needed(0, 4), 0);
+        assert_eq!(
+            data.allocation_needed(0, CHUNK_SIZE as u64 + 1),
+            CHUNK_SIZE as u64
+        );
+        assert_eq!(data.allocation_needed(5 * CHUNK_SIZE as u64, 0), 0);
+    }
+
+    #[test_log::test]
+    fn punch_hole() {
+        // Arrange
+        let mut data = FileData::default();
+        data.write(0, &[1; 3 * CHUNK_SIZE]);
+
+        // Act
+
+        data.punch_hole(10, 2 * CHUNK_SIZE as u64);
+
+        // Assert
+
+        assert_eq!(data.len(), 3 * CHUNK_SIZE as u64);
+        assert_eq!(data.allocated(), 2 * CHUNK_SIZE as u64);
+        let content = read_all(&data);
+        assert!(content[..10].iter().all(|&b| b == 1));
+        assert!(content[10..2 * CHUNK_SIZE + 10].iter().all(|&b| b == 0));
+        assert!(content[2 * CHUNK_SIZE + 10..].iter().all(|&b| b == 1));
+    }
+
+    #[test_log::test]
+    fn seek_data_and_hole() {
+        // Arrange
+        let mut data = FileData::default();
+
+        // Act
+
+        data.write(2 * CHUNK_SIZE as u64, b"test");
+        data.truncate(4 * CHUNK_SIZE as u64);
+
+        // Assert
+
+        assert_eq!(data.seek_data(0), Some(2 * CHUNK_SIZE as u64));
+        assert_eq!(
+            data.seek_data(2 * CHUNK_SIZE as u64 + 1),
+            Some(2 * CHUNK_SIZE as u64 + 1)
+        );
+        assert_eq!(data.seek_data(3 * CHUNK_SIZE as u64), None);
+        assert_eq!(data.seek_hole(0), Some(0));
+        assert_eq!(
+            data.seek_hole(2 * CHUNK_SIZE as u64),
+            Some(3 * CHUNK_SIZE as u64)
+        );
+        assert_eq!(data.seek_hole(4 * CHUNK_SIZE as u64), None);
+    }
+}
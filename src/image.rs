@@ -0,0 +1,515 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fs,
+    io::{self, BufReader, Read, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, atomic::Ordering},
+    time::SystemTime,
+};
+
+use log::info;
+
+use crate::{
+    File, FileData, Folder, InnerNode, Inode, MAX_FILE_SIZE, MyFileSystem, Node, ROOT_INODE,
+    SPECIAL_FILE_TYPES, Special, Symlink, Unpoisoned, from_unix_time, journal, to_unix_time,
+};
+
+/// Every image starts with these bytes
+const MAGIC: &[u8; 8] = b"MYFUSEFS";
+
+/// The version of the image format that is written.
+/// Older versions are still read, so every change to the format has to bump it.
+///
+/// - 1: The first version
+/// - 2: Adds the id of the journal that continues the image
+/// - 3: Adds device nodes, FIFOs and sockets
+/// - 4: Adds the generation of every node and of every free inode
+pub const VERSION: u32 = 4;
+
+const KIND_FILE: u8 = 0;
+const KIND_FOLDER: u8 = 1;
+const KIND_SYMLINK: u8 = 2;
+const KIND_SPECIAL: u8 = 3;
+
+/// The nodes of an image together with the id of the journal that continues it
+#[derive(Default)]
+pub struct Image {
+    pub journal_id: u64,
+    pub nodes: BTreeMap<Inode, Node>,
+    /// This BTree maps free inodes to the generation of the next node that uses them
+    pub free: BTreeMap<Inode, u64>,
+    /// Older images do not list their free inodes, so there may be gaps between their nodes
+    pub lists_free: bool,
+}
+
+/// Writes all nodes that are reachable by name to the image at `path` and continues the journal in a new file.
+/// Requests only wait while the snapshot is taken, not while it is written.
+pub fn save(fs: &MyFileSystem, path: &Path) -> io::Result<()> {
+    // A save running at the same time could replace a newer image with an older one
+    let _saving = fs.save_lock.lock().unpoisoned();
+    let (snapshot, journal_id) = {
+        // No node can change while the snapshot is taken, so it is consistent
+        let _snapshot = fs.snapshot_lock.write().unpoisoned();
+        let snapshot = snapshot(fs)?;
+        // Changes made from now on are recorded in a new journal, which continues the new image
+        let journal_id = match &fs.journal {
+            Some(journal) => journal.rotate()?,
+            None => 0,
+        };
+        (snapshot, journal_id)
+    };
+    write(path, journal_id, &snapshot)?;
+    // A crash before this leaves the previous journal behind, which is skipped because of its id
+    match &fs.journal {
+        Some(journal) => journal.remove_previous()?,
+        None => journal::remove_previous(&journal::path(path))?,
+    }
+    info!("Saved image {}", path.display());
+    Ok(())
+}
+
+/// The nodes and free inodes of the image, which have to be taken while no node can change
+pub fn snapshot(fs: &MyFileSystem) -> io::Result<Vec<u8>> {
+    let nodes: Vec<_> = fs
+        .nodes
+        .read()
+        .unpoisoned()
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+    let nodes: Vec<_> = nodes.iter().map(|node| node.read().unpoisoned()).collect();
+    // Nodes without links only live on until they are closed, so they are not persisted.
+    // Their inodes are free after a restore like the ones in the queue.
+    let (nodes, unlinked): (Vec<_>, Vec<_>) = nodes.iter().partition(|node| node.nlink > 0);
+    let free: Vec<(Inode, u64)> = fs
+        .reusable_inode_queue
+        .read()
+        .unpoisoned()
+        .iter()
+        .copied()
+        .chain(
+            unlinked
+                .iter()
+                .map(|node| (node.inode, node.generation.wrapping_add(1))),
+        )
+        .collect();
+
+    let mut w = Vec::new();
+    write_u64(&mut w, nodes.len() as u64)?;
+    for node in nodes {
+        write_node(&mut w, node)?;
+    }
+    write_u64(&mut w, free.len() as u64)?;
+    for (inode, generation) in free {
+        write_u64(&mut w, inode)?;
+        write_u64(&mut w, generation)?;
+    }
+    Ok(w)
+}
+
+/// Writes the image with the `snapshot` that is continued by the journal with the given id.
+/// The image is written next to `path` first and then renamed, so a crash never leaves a broken image.
+pub fn write(path: &Path, journal_id: u64, snapshot: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let mut file = fs::File::create(&temp_path)?;
+    let mut header = MAGIC.to_vec();
+    write_u32(&mut header, VERSION)?;
+    write_u64(&mut header, journal_id)?;
+    file.write_all(&header)?;
+    file.write_all(snapshot)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sync_parent(path)
+}
+
+/// Makes a rename to `path` durable by syncing the folder containing it
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) => fs::File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Reads the image at `path`
+pub fn read(path: &Path) -> io::Result<Image> {
+    let mut r = BufReader::new(fs::File::open(path)?);
+    let mut magic = [0; MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a my-fuse image"));
+    }
+    let version = read_u32(&mut r)?;
+    if version == 0 || version > VERSION {
+        return Err(invalid_data(format!("unsupported image version {version}")));
+    }
+    // Images without a journal id are continued by the first journal
+    let journal_id = if version >= 2 { read_u64(&mut r)? } else { 0 };
+
+    let count = read_u64(&mut r)?;
+    let mut nodes: BTreeMap<Inode, Node> = BTreeMap::new();
+    for _ in 0..count {
+        let node = read_node(&mut r, version)?;
+        if node.inode == 0 || nodes.contains_key(&node.inode) {
+            return Err(invalid_data(format!("invalid inode {}", node.inode)));
+        }
+        nodes.insert(node.inode, node);
+    }
+    // Older images start every free inode at generation 0
+    let mut free = BTreeMap::new();
+    if version >= 4 {
+        for _ in 0..read_u64(&mut r)? {
+            let inode = read_u64(&mut r)?;
+            free.insert(inode, read_u64(&mut r)?);
+        }
+    }
+    info!("Read image {} with {count} nodes", path.display());
+    Ok(Image {
+        journal_id,
+        nodes,
+        free,
+        lists_free: version >= 4,
+    })
+}
+
+/// Fills the empty filesystem with the nodes of the image.
+/// Nodes that are not reachable by name from the root anymore are left out.
+pub fn install(fs: &MyFileSystem, image: Image) -> io::Result<()> {
+    let Image {
+        mut nodes,
+        mut free,
+        lists_free,
+        ..
+    } = image;
+    let reachable = reachable(&nodes)?;
+    // The inodes of nodes that were removed by the journal are free again with the next generation
+    for node in nodes
+        .values()
+        .filter(|node| !reachable.contains(&node.inode))
+    {
+        free.insert(node.inode, node.generation.wrapping_add(1));
+    }
+    nodes.retain(|inode, _| reachable.contains(inode));
+    link_parents(&mut nodes);
+
+    let count = nodes.len() as u64;
+    let used_bytes = nodes.values().map(Node::content_size).sum();
+    // Free inodes past the last node keep their generation as well
+    let last_inode = nodes.keys().chain(free.keys()).max().copied().unwrap_or(0);
+    // Every inode up to the last one is a node or free, so a broken image can not make the table huge.
+    // The gaps of older images are only caught when the table does not fit into memory.
+    let known = count
+        + free
+            .keys()
+            .filter(|inode| !nodes.contains_key(inode))
+            .count() as u64;
+    if lists_free && last_inode > known {
+        return Err(invalid_data(format!(
+            "inode {last_inode} is past the {known} inodes of the image"
+        )));
+    }
+    let mut table: Vec<Option<Arc<RwLock<Node>>>> = Vec::new();
+    usize::try_from(last_inode)
+        .ok()
+        .and_then(|len| table.try_reserve_exact(len).ok())
+        .ok_or_else(|| invalid_data(format!("inode {last_inode} is too large")))?;
+    table.resize(last_inode as usize, None);
+    let mut queue = fs.reusable_inode_queue.write().unpoisoned();
+    queue.clear();
+    for inode in 1..=last_inode {
+        match nodes.remove(&inode) {
+            Some(node) => table[inode as usize - 1] = Some(Arc::new(RwLock::new(node))),
+            None => queue.push_back((inode, free.get(&inode).copied().unwrap_or(0))),
+        }
+    }
+    fs.used_inodes.store(count, Ordering::Release);
+    fs.used_bytes.store(used_bytes, Ordering::Release);
+    *fs.nodes.write().unpoisoned() = table;
+    Ok(())
+}
+
+/// Finds the nodes that are reachable by name from the root.
+/// Fails if the root is not a folder or an entry points to a node that is missing from the image.
+fn reachable(nodes: &BTreeMap<Inode, Node>) -> io::Result<BTreeSet<Inode>> {
+    match nodes.get(&ROOT_INODE) {
+        Some(Node {
+            inner: InnerNode::Folder(_),
+            ..
+        }) => {}
+        _ => return Err(invalid_data("the image has no root folder")),
+    }
+    let mut reachable = BTreeSet::from([ROOT_INODE]);
+    let mut folders = vec![ROOT_INODE];
+    while let Some(inode) = folders.pop() {
+        let Some(InnerNode::Folder(folder)) = nodes.get(&inode).map(|node| &node.inner) else {
+            continue;
+        };
+        for (name, child) in folder.iter() {
+            let Some(node) = nodes.get(&child) else {
+                return Err(invalid_data(format!(
+                    "entry {name:?} of inode {inode} points to missing inode {child}"
+                )));
+            };
+            if reachable.insert(child) && matches!(node.inner, InnerNode::Folder(_)) {
+                folders.push(child);
+            }
+        }
+    }
+    Ok(reachable)
+}
+
+/// Points every folder to the folder that has an entry for it
+fn link_parents(nodes: &mut BTreeMap<Inode, Node>) {
+    let parents: Vec<(Inode, Inode)> = nodes
+        .values()
+        .filter_map(|node| match &node.inner {
+            InnerNode::Folder(folder) => Some((node.inode, folder)),
+            _ => None,
+        })
+        .flat_map(|(parent, folder)| folder.iter().map(move |(_, child)| (child, parent)))
+        .collect();
+    for (child, parent) in parents {
+        if let Some(InnerNode::Folder(folder)) = nodes.get_mut(&child).map(|node| &mut node.inner) {
+            folder.parent = parent;
+        }
+    }
+}
+
+pub fn write_node(w: &mut impl Write, node: &Node) -> io::Result<()> {
+    write_u64(w, node.inode)?;
+    write_u64(w, node.generation)?;
+    write_u32(w, node.nlink)?;
+    write_u32(w, node.uid)?;
+    write_u32(w, node.gid)?;
+    write_u32(w, node.mode)?;
+    for time in [node.atime, node.mtime, node.ctime, node.crtime] {
+        write_time(w, time)?;
+    }
+    write_u32(w, node.xattrs.len() as u32)?;
+    for (name, value) in &node.xattrs {
+        write_bytes(w, name)?;
+        write_bytes(w, value)?;
+    }
+
+    match &node.inner {
+        InnerNode::File(file) => {
+            let data = file.data.read().unpoisoned();
+            w.write_all(&[KIND_FILE])?;
+            write_u64(w, data.len())?;
+            write_u64(w, data.chunks().count() as u64)?;
+            for (offset, chunk) in data.chunks() {
+                write_u64(w, offset)?;
+                write_bytes(w, chunk)?;
+            }
+        }
+        InnerNode::Folder(folder) => {
+            w.write_all(&[KIND_FOLDER])?;
+            write_u64(w, folder.len() as u64)?;
+            for (name, inode) in folder.iter() {
+                write_bytes(w, name.as_bytes())?;
+                write_u64(w, inode)?;
+            }
+        }
+        InnerNode::Symlink(symlink) => {
+            w.write_all(&[KIND_SYMLINK])?;
+            write_bytes(w, &symlink.target)?;
+        }
+        InnerNode::Special(special) => {
+            w.write_all(&[KIND_SPECIAL])?;
+            write_u32(w, special.file_type)?;
+            write_u32(w, special.rdev)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn read_node(r: &mut impl Read, version: u32) -> io::Result<Node> {
+    // Fields added later are read depending on the version
+    let inode = read_u64(r)?;
+    let generation = if version >= 4 { read_u64(r)? } else { 0 };
+    let nlink = read_u32(r)?;
+    let uid = read_u32(r)?;
+    let gid = read_u32(r)?;
+    let mode = read_u32(r)?;
+    let atime = read_time(r)?;
+    let mtime = read_time(r)?;
+    let ctime = read_time(r)?;
+    let crtime = read_time(r)?;
+    let mut xattrs = BTreeMap::new();
+    for _ in 0..read_u32(r)? {
+        let name = read_bytes(r)?;
+        let value = read_bytes(r)?;
+        xattrs.insert(name, value);
+    }
+
+    let mut kind = [0];
+    r.read_exact(&mut kind)?;
+    let inner = match kind[0] {
+        KIND_FILE => {
+            let size = read_u64(r)?.min(MAX_FILE_SIZE);
+            let mut data = FileData::default();
+            for _ in 0..read_u64(r)? {
+                let offset = read_u64(r)?;
+                let chunk = read_bytes(r)?;
+                if offset.saturating_add(chunk.len() as u64) > MAX_FILE_SIZE {
+                    return Err(invalid_data(format!("chunk at {offset} is out of bounds")));
+                }
+                data.write(offset, &chunk);
+            }
+            // Chunks may reach beyond the end of the file
+            data.truncate(size);
+            InnerNode::File(File {
+                data: Arc::new(RwLock::new(data)),
+            })
+        }
+        KIND_FOLDER => {
+            // The parent is not stored, install finds it through the entries
+            let mut folder = Folder::new(ROOT_INODE);
+            for _ in 0..read_u64(r)? {
+                let name = OsString::from_vec(read_bytes(r)?);
+                folder.insert(&name, read_u64(r)?);
+            }
+            InnerNode::Folder(folder)
+        }
+        KIND_SYMLINK => InnerNode::Symlink(Symlink {
+            target: read_bytes(r)?,
+        }),
+        KIND_SPECIAL => {
+            let file_type = read_u32(r)?;
+            if !SPECIAL_FILE_TYPES.contains(&file_type) {
+                return Err(invalid_data(format!("unknown file type {file_type:o}")));
+            }
+            InnerNode::Special(Special {
+                file_type,
+                rdev: read_u32(r)?,
+            })
+        }
+        kind => return Err(invalid_data(format!("unknown node kind {kind}"))),
+    };
+
+    let mut node = Node::new(inode, nlink, uid, gid, mode, inner);
+    node.atime = atime;
+    node.mtime = mtime;
+    node.ctime = ctime;
+    node.crtime = crtime;
+    node.xattrs = xattrs;
+    node.generation = generation;
+    Ok(node)
+}
+
+/// The path the image is written to before it replaces the one at `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+pub fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64(w: &mut impl Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_u32(w, bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+pub fn write_time(w: &mut impl Write, time: SystemTime) -> io::Result<()> {
+    let (secs, nanos) = to_unix_time(time);
+    write_u64(w, secs as u64)?;
+    write_u32(w, nanos)
+}
+
+pub fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn read_time(r: &mut impl Read) -> io::Result<SystemTime> {
+    let secs = read_u64(r)? as i64;
+    let nanos = read_u32(r)?;
+    from_unix_time(secs, nanos as i64)
+        .ok_or_else(|| invalid_data(format!("Time {secs} is out of range")))
+}
+
+pub fn read_bytes(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u32(r)? as usize;
+    let mut buf = Vec::new();
+    // The length is not trusted with a preallocation, so a broken image fails instead of aborting
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAGIC, VERSION, install, read, write_node, write_u32, write_u64};
+    use crate::{MountOptions, MyFileSystem, Node, ROOT_INODE};
+    use std::{fs, io};
+
+    #[test_log::test]
+    fn reject_newer_version() {
+        // Arrange
+        let dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let path = dir.path().join("image");
+        let mut image = MAGIC.to_vec();
+        image.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, image).unwrap();
+
+        // Act
+
+        let result = read(&path);
+
+        // Assert
+
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test_log::test]
+    fn reject_inode_past_the_image() {
+        // Arrange
+        let dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let path = dir.path().join("image");
+        let mut image = MAGIC.to_vec();
+        write_u32(&mut image, VERSION).unwrap();
+        write_u64(&mut image, 0).unwrap();
+        write_u64(&mut image, 1).unwrap();
+        let root = Node::new_folder(ROOT_INODE, ROOT_INODE, 0, 0, 0o755);
+        write_node(&mut image, &root).unwrap();
+        // The only free inode is far past the root, which would need a huge table
+        write_u64(&mut image, 1).unwrap();
+        write_u64(&mut image, 1 << 40).unwrap();
+        write_u64(&mut image, 0).unwrap();
+        fs::write(&path, image).unwrap();
+        let filesystem = MyFileSystem::new(&MountOptions::default());
+
+        // Act
+
+        let result = read(&path).and_then(|image| install(&filesystem, image));
+
+        // Assert
+
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,622 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use log::{debug, error, info, warn};
+
+use crate::{
+    InnerNode, Inode, MAX_FILE_SIZE, Node, SyncPolicy, Unpoisoned,
+    image::{
+        self, invalid_data, read_bytes, read_node, read_time, read_u32, read_u64, write_bytes,
+        write_node, write_time, write_u32, write_u64,
+    },
+};
+
+/// Every journal starts with these bytes
+const MAGIC: &[u8; 8] = b"MYFUSEJL";
+
+/// The version of the journal format that is written
+const VERSION: u32 = 1;
+
+/// The magic, the two versions and the image id
+const HEADER_SIZE: usize = MAGIC.len() + 16;
+
+const RECORD_CREATE: u8 = 0;
+const RECORD_LINK: u8 = 1;
+const RECORD_UNLINK: u8 = 2;
+const RECORD_ATTR: u8 = 3;
+const RECORD_WRITE: u8 = 4;
+const RECORD_TRUNCATE: u8 = 5;
+const RECORD_ALLOCATE: u8 = 6;
+const RECORD_PUNCH_HOLE: u8 = 7;
+const RECORD_SET_XATTR: u8 = 8;
+const RECORD_REMOVE_XATTR: u8 = 9;
+
+/// The journal that belongs to the image at `image`
+pub fn path(image: &Path) -> PathBuf {
+    with_suffix(image, ".journal")
+}
+
+/// The journal at `path` is kept here while the image it was continued for is written
+fn previous_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".prev")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Removes the previous journal of the journal at `path` once the image contains it
+pub fn remove_previous(path: &Path) -> io::Result<()> {
+    match fs::remove_file(previous_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// An append-only log of every change since the image was saved.
+/// Replaying it on top of the image restores the filesystem after a crash.
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<JournalFile>,
+    policy: SyncPolicy,
+}
+
+struct JournalFile {
+    file: fs::File,
+    /// The id of the image this journal continues
+    id: u64,
+    last_sync: Instant,
+    /// A failed append could not be removed again, so nothing may be appended after it
+    broken: bool,
+    /// The image of the last rotation was not written yet, so the previous journal is still needed
+    previous_needed: bool,
+    /// Makes the next append fail after writing this many bytes of its frame
+    #[cfg(test)]
+    fail_after: Option<usize>,
+}
+
+impl JournalFile {
+    fn new(file: fs::File, id: u64) -> Self {
+        Self {
+            file,
+            id,
+            last_sync: Instant::now(),
+            broken: false,
+            previous_needed: false,
+            #[cfg(test)]
+            fail_after: None,
+        }
+    }
+
+    /// Writes the frame of one operation and syncs it as often as the policy asks for
+    fn write_frame(&mut self, frame: &[u8], policy: SyncPolicy) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(len) = self.fail_after.take() {
+            self.file.write_all(&frame[..len])?;
+            return Err(io::Error::other("failing append"));
+        }
+        self.file.write_all(frame)?;
+        let sync = match policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Cuts off what a failed append left behind after `len` bytes
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        // Devices like /dev/full have no length and nothing to cut off
+        if self.file.metadata()?.len() != len {
+            self.file.set_len(len)?;
+            self.file.seek(SeekFrom::Start(len))?;
+        }
+        Ok(())
+    }
+}
+
+impl Journal {
+    /// Opens the journal at `path` that continues the image with the given journal id.
+    /// A journal of another image was already saved into it and is started over.
+    pub fn open(path: &Path, id: u64, policy: SyncPolicy) -> io::Result<Self> {
+        let continues = matches!(read_header(path), Ok((_, header_id)) if header_id == id);
+        let file = fs::File::options().create(true).append(true).open(path)?;
+        let journal = Self {
+            path: path.to_owned(),
+            file: Mutex::new(JournalFile::new(file, id)),
+            policy,
+        };
+        if !continues {
+            journal.reset(id)?;
+        }
+        Ok(journal)
+    }
+
+    /// A journal on a device that is always full, so every append fails with ENOSPC
+    #[cfg(test)]
+    pub fn full() -> Self {
+        let file = fs::File::options().write(true).open("/dev/full").unwrap();
+        Self {
+            path: PathBuf::from("/dev/full"),
+            file: Mutex::new(JournalFile::new(file, 0)),
+            policy: SyncPolicy::Never,
+        }
+    }
+
+    /// Starts an empty journal after its content was saved into the image with the given id
+    fn reset(&self, id: u64) -> io::Result<()> {
+        let mut journal = self.file.lock().unpoisoned();
+        journal.file.set_len(0)?;
+        journal.file.write_all(&header(id)?)?;
+        journal.file.sync_all()?;
+        journal.id = id;
+        journal.last_sync = Instant::now();
+        journal.broken = false;
+        Ok(())
+    }
+
+    /// Continues the journal in a new file for the image that is about to be saved and returns its journal id.
+    /// The current file is kept as the previous journal until that image is written,
+    /// so a crash in between finds every change in one of the two.
+    pub fn rotate(&self) -> io::Result<u64> {
+        let mut journal = self.file.lock().unpoisoned();
+        let previous_path = previous_path(&self.path);
+        if journal.previous_needed {
+            // The last save failed, so the previous journal must not be replaced by the current one.
+            // Both are merged into the current file with the id of the previous one instead.
+            // Replay prefers that file, because it contains the previous journal completely.
+            let previous_id = journal.id - 1;
+            let mut merged = header(previous_id)?;
+            for path in [&previous_path, &self.path] {
+                let mut r = BufReader::new(fs::File::open(path)?);
+                r.read_exact(&mut [0; HEADER_SIZE])?;
+                while let Some(payload) = read_payload(&mut r, path)? {
+                    merged.extend(frame(&payload)?);
+                }
+            }
+            let temp_path = with_suffix(&self.path, ".tmp");
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(&merged)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &self.path)?;
+            journal.file = fs::File::options().append(true).open(&self.path)?;
+            journal.id = previous_id;
+        }
+        let id = journal.id + 1;
+        // The new journal only replaces the current one once its header is complete
+        let temp_path = with_suffix(&self.path, ".tmp");
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(&header(id)?)?;
+        file.sync_all()?;
+        fs::rename(&self.path, &previous_path)?;
+        journal.previous_needed = true;
+        fs::rename(&temp_path, &self.path)?;
+        image::sync_parent(&self.path)?;
+        journal.file = fs::File::options().append(true).open(&self.path)?;
+        journal.id = id;
+        journal.last_sync = Instant::now();
+        // The snapshot of the image contains everything, so the new journal can be appended to again
+        journal.broken = false;
+        Ok(id)
+    }
+
+    /// Removes the previous journal once the image of the last rotation is written
+    pub fn remove_previous(&self) -> io::Result<()> {
+        let mut journal = self.file.lock().unpoisoned();
+        // The image contains the previous journal, even if it can not be removed
+        journal.previous_needed = false;
+        remove_previous(&self.path)
+    }
+
+    /// Appends the records of one operation.
+    /// They are written with a single write, so they survive the process being killed right after.
+    /// If the append fails, the operation is undone and its frame is cut off again,
+    /// so replay neither brings it back nor stops at it before later operations.
+    pub fn append(&self, transaction: Transaction) -> io::Result<()> {
+        let Some(payload) = transaction.buf.filter(|buf| !buf.is_empty()) else {
+            return Ok(());
+        };
+        let frame = frame(&payload)?;
+
+        let mut journal = self.file.lock().unpoisoned();
+        if journal.broken {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        let len = journal.file.metadata()?.len();
+        let result = journal.write_frame(&frame, self.policy);
+        if result.is_err()
+            && let Err(e) = journal.truncate(len)
+        {
+            error!(
+                "Journal {} is unusable until the next save: {e}",
+                self.path.display()
+            );
+            journal.broken = true;
+        }
+        result
+    }
+}
+
+/// The records of one operation. An operation is replayed completely or not at all.
+/// Without a journal nothing is recorded.
+pub struct Transaction {
+    buf: Option<Vec<u8>>,
+}
+
+impl Transaction {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            buf: enabled.then(Vec::new),
+        }
+    }
+
+    /// Starts a record and returns the buffer its fields are written to
+    fn record(&mut self, tag: u8) -> Option<&mut Vec<u8>> {
+        let buf = self.buf.as_mut()?;
+        buf.push(tag);
+        Some(buf)
+    }
+
+    // Writing into the buffer can not fail, so the results are unwrapped
+
+    /// A new node without entries or content
+    pub fn create(&mut self, node: &Node) {
+        if let Some(buf) = self.record(RECORD_CREATE) {
+            write_node(buf, node).unwrap();
+        }
+    }
+
+    pub fn link(&mut self, parent: Inode, name: &OsStr, inode: Inode) {
+        if let Some(buf) = self.record(RECORD_LINK) {
+            write_u64(buf, parent).unwrap();
+            write_bytes(buf, name.as_bytes()).unwrap();
+            write_u64(buf, inode).unwrap();
+        }
+    }
+
+    pub fn unlink(&mut self, parent: Inode, name: &OsStr) {
+        if let Some(buf) = self.record(RECORD_UNLINK) {
+            write_u64(buf, parent).unwrap();
+            write_bytes(buf, name.as_bytes()).unwrap();
+        }
+    }
+
+    /// The link count, owner, permissions and times of the node
+    pub fn attr(&mut self, node: &Node) {
+        if let Some(buf) = self.record(RECORD_ATTR) {
+            write_u64(buf, node.inode).unwrap();
+            write_u32(buf, node.nlink).unwrap();
+            write_u32(buf, node.uid).unwrap();
+            write_u32(buf, node.gid).unwrap();
+            write_u32(buf, node.mode).unwrap();
+            for time in [node.atime, node.mtime, node.ctime] {
+                write_time(buf, time).unwrap();
+            }
+        }
+    }
+
+    pub fn write(&mut self, inode: Inode, offset: u64, data: &[u8]) {
+        if let Some(buf) = self.record(RECORD_WRITE) {
+            write_u64(buf, inode).unwrap();
+            write_u64(buf, offset).unwrap();
+            write_bytes(buf, data).unwrap();
+        }
+    }
+
+    pub fn truncate(&mut self, inode: Inode, size: u64) {
+        if let Some(buf) = self.record(RECORD_TRUNCATE) {
+            write_u64(buf, inode).unwrap();
+            write_u64(buf, size).unwrap();
+        }
+    }
+
+    pub fn allocate(&mut self, inode: Inode, offset: u64, length: u64) {
+        if let Some(buf) = self.record(RECORD_ALLOCATE) {
+            write_u64(buf, inode).unwrap();
+            write_u64(buf, offset).unwrap();
+            write_u64(buf, length).unwrap();
+        }
+    }
+
+    pub fn punch_hole(&mut self, inode: Inode, offset: u64, length: u64) {
+        if let Some(buf) = self.record(RECORD_PUNCH_HOLE) {
+            write_u64(buf, inode).unwrap();
+            write_u64(buf, offset).unwrap();
+            write_u64(buf, length).unwrap();
+        }
+    }
+
+    pub fn set_xattr(&mut self, inode: Inode, name: &[u8], value: &[u8]) {
+        if let Some(buf) = self.record(RECORD_SET_XATTR) {
+            write_u64(buf, inode).unwrap();
+            write_bytes(buf, name).unwrap();
+            write_bytes(buf, value).unwrap();
+        }
+    }
+
+    pub fn remove_xattr(&mut self, inode: Inode, name: &[u8]) {
+        if let Some(buf) = self.record(RECORD_REMOVE_XATTR) {
+            write_u64(buf, inode).unwrap();
+            write_bytes(buf, name).unwrap();
+        }
+    }
+}
+
+/// Applies the journal at `path` to the nodes of the image with the given journal id.
+/// If the process died while the image was saved, the previous journal is applied first
+/// and continued by the one at `path`.
+/// A journal at `path` with the id of the image already contains the previous one, which is skipped then.
+/// Returns the number of replayed operations.
+pub fn replay(path: &Path, id: u64, nodes: &mut BTreeMap<Inode, Node>) -> io::Result<u64> {
+    let (previous, id) = match read_header(path) {
+        Ok((_, header_id)) if header_id == id => (0, id),
+        _ => match replay_file(&previous_path(path), id, nodes)? {
+            Some(operations) => (operations, id + 1),
+            None => (0, id),
+        },
+    };
+    Ok(previous + replay_file(path, id, nodes)?.unwrap_or(0))
+}
+
+/// Applies the journal file at `path` if it continues the image with the given journal id.
+/// Returns the number of replayed operations or None if the journal belongs to another image.
+/// An operation that was only partly written when the process died is dropped.
+fn replay_file(path: &Path, id: u64, nodes: &mut BTreeMap<Inode, Node>) -> io::Result<Option<u64>> {
+    let (node_version, header_id) = match read_header(path) {
+        Ok(header) => header,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if header_id != id {
+        info!(
+            "Skipping journal {}, it is already in the image",
+            path.display()
+        );
+        return Ok(None);
+    }
+
+    let mut r = BufReader::new(fs::File::open(path)?);
+    r.read_exact(&mut [0; HEADER_SIZE])?;
+    let mut operations = 0;
+    while let Some(payload) = read_payload(&mut r, path)? {
+        let mut records = payload.as_slice();
+        while !records.is_empty() {
+            apply(&mut records, node_version, nodes)?;
+        }
+        operations += 1;
+    }
+    info!("Replayed {operations} operations from {}", path.display());
+    Ok(Some(operations))
+}
+
+/// Applies the first record in `r` to the nodes.
+/// Records of nodes that are missing are skipped. Those nodes had no links anymore when the image was saved,
+/// but were still open and changed afterwards. They are dropped at restore anyway.
+fn apply(r: &mut &[u8], node_version: u32, nodes: &mut BTreeMap<Inode, Node>) -> io::Result<()> {
+    let mut tag = [0];
+    r.read_exact(&mut tag)?;
+    match tag[0] {
+        RECORD_CREATE => {
+            let node = read_node(r, node_version)?;
+            if node.inode == 0 {
+                return Err(invalid_data("journal creates inode 0"));
+            }
+            nodes.insert(node.inode, node);
+        }
+        RECORD_LINK | RECORD_UNLINK => {
+            let parent = read_u64(r)?;
+            let name = OsString::from_vec(read_bytes(r)?);
+            let inode = match tag[0] {
+                RECORD_LINK => Some(read_u64(r)?),
+                _ => None,
+            };
+            let Some(node) = node(nodes, parent) else {
+                return Ok(());
+            };
+            let InnerNode::Folder(folder) = &mut node.inner else {
+                return Err(invalid_data(format!("inode {parent} is not a folder")));
+            };
+            match inode {
+                Some(inode) => folder.insert(&name, inode),
+                None => {
+                    folder.remove(&name);
+                }
+            }
+        }
+        RECORD_ATTR => {
+            let inode = read_u64(r)?;
+            let nlink = read_u32(r)?;
+            let uid = read_u32(r)?;
+            let gid = read_u32(r)?;
+            let mode = read_u32(r)?;
+            let atime = read_time(r)?;
+            let mtime = read_time(r)?;
+            let ctime = read_time(r)?;
+            let Some(node) = node(nodes, inode) else {
+                return Ok(());
+            };
+            node.nlink = nlink;
+            node.uid = uid;
+            node.gid = gid;
+            node.mode = mode;
+            node.atime = atime;
+            node.mtime = mtime;
+            node.ctime = ctime;
+        }
+        RECORD_WRITE | RECORD_TRUNCATE | RECORD_ALLOCATE | RECORD_PUNCH_HOLE => {
+            let inode = read_u64(r)?;
+            let offset = read_u64(r)?;
+            let (buf, end) = match tag[0] {
+                RECORD_WRITE => {
+                    let buf = read_bytes(r)?;
+                    let end = offset.saturating_add(buf.len() as u64);
+                    (buf, end)
+                }
+                RECORD_TRUNCATE => (Vec::new(), offset),
+                _ => (Vec::new(), offset.saturating_add(read_u64(r)?)),
+            };
+            if end > MAX_FILE_SIZE {
+                return Err(invalid_data(format!(
+                    "change of inode {inode} is out of bounds"
+                )));
+            }
+            let Some(node) = node(nodes, inode) else {
+                return Ok(());
+            };
+            let InnerNode::File(file) = &node.inner else {
+                return Err(invalid_data(format!("inode {inode} is not a file")));
+            };
+            let mut data = file.data.write().unpoisoned();
+            match tag[0] {
+                RECORD_WRITE => data.write(offset, &buf),
+                RECORD_TRUNCATE => data.truncate(offset),
+                RECORD_ALLOCATE => data.allocate(offset, end - offset),
+                _ => data.punch_hole(offset, end - offset),
+            }
+        }
+        RECORD_SET_XATTR | RECORD_REMOVE_XATTR => {
+            let inode = read_u64(r)?;
+            let name = read_bytes(r)?;
+            let value = match tag[0] {
+                RECORD_SET_XATTR => Some(read_bytes(r)?),
+                _ => None,
+            };
+            let Some(node) = node(nodes, inode) else {
+                return Ok(());
+            };
+            match value {
+                Some(value) => node.xattrs.insert(name, value),
+                None => node.xattrs.remove(&name),
+            };
+        }
+        tag => return Err(invalid_data(format!("unknown journal record {tag}"))),
+    }
+    Ok(())
+}
+
+/// The node `inode`, which is missing if it was dropped from the image or not created yet
+fn node(nodes: &mut BTreeMap<Inode, Node>, inode: Inode) -> Option<&mut Node> {
+    let node = nodes.get_mut(&inode);
+    if node.is_none() {
+        debug!("Skipping journal record of missing inode {inode}");
+    }
+    node
+}
+
+/// The header of a journal that continues the image with the given journal id
+fn header(id: u64) -> io::Result<Vec<u8>> {
+    let mut header = MAGIC.to_vec();
+    write_u32(&mut header, VERSION)?;
+    write_u32(&mut header, image::VERSION)?;
+    write_u64(&mut header, id)?;
+    Ok(header)
+}
+
+/// Returns the version of the node format and the id of the image the journal continues
+fn read_header(path: &Path) -> io::Result<(u32, u64)> {
+    let mut r = fs::File::open(path)?;
+    let mut magic = [0; MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a my-fuse journal"));
+    }
+    let version = read_u32(&mut r)?;
+    if version == 0 || version > VERSION {
+        return Err(invalid_data(format!(
+            "unsupported journal version {version}"
+        )));
+    }
+    let node_version = read_u32(&mut r)?;
+    if node_version == 0 || node_version > image::VERSION {
+        return Err(invalid_data(format!(
+            "unsupported node version {node_version}"
+        )));
+    }
+    Ok((node_version, read_u64(&mut r)?))
+}
+
+/// The operation with the given records, prefixed with their length and checksum
+fn frame(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut frame = Vec::with_capacity(payload.len() + 12);
+    write_u32(&mut frame, payload.len() as u32)?;
+    write_u64(&mut frame, checksum(payload))?;
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// The records of the next operation in the journal at `path`, or `None` at its end
+fn read_payload(r: &mut impl Read, path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut frame_header = [0; 12];
+    match r.read_exact(&mut frame_header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(frame_header[..4].try_into().unwrap()) as u64;
+    let sum = u64::from_le_bytes(frame_header[4..].try_into().unwrap());
+    let mut payload = Vec::new();
+    r.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len || checksum(&payload) != sum {
+        warn!("Journal {} ends with a torn operation", path.display());
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+/// The FNV-1a hash of the data. It detects operations that were only partly written.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Journal, Transaction, replay};
+    use crate::{Node, SyncPolicy};
+    use std::collections::BTreeMap;
+
+    #[test_log::test]
+    fn append_after_failed_append() {
+        // Arrange
+        let dir = tempdir::TempDir::new("my-fuse-journal").unwrap();
+        let path = dir.path().join("image.journal");
+        let journal = Journal::open(&path, 0, SyncPolicy::Always).unwrap();
+        let append = |inode| {
+            let mut transaction = Transaction::new(true);
+            transaction.create(&Node::new_file(inode, 0, 0, 0o644));
+            journal.append(transaction)
+        };
+        append(2).unwrap();
+
+        // Act
+
+        // Only a part of the frame is written before the append fails
+        journal.file.lock().unwrap().fail_after = Some(5);
+        let failed = append(3);
+        let after = append(4);
+        let mut nodes = BTreeMap::new();
+        let replayed = replay(&path, 0, &mut nodes);
+
+        // Assert
+
+        failed.unwrap_err();
+        after.unwrap();
+        assert_eq!(replayed.unwrap(), 2);
+        assert_eq!(nodes.keys().copied().collect::<Vec<_>>(), [2, 4]);
+    }
+}
@@ -1,44 +1,143 @@
 use std::{
     collections::{BTreeMap, LinkedList},
-    ffi::CStr,
+    ffi::{CStr, OsStr, OsString},
     io::{self},
-    path::Path,
-    sync::{Arc, RwLock, Weak},
-    time::{Duration, SystemTime},
+    num::NonZeroUsize,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LockResult, Mutex, PoisonError, RwLock, Weak,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use fuse_backend_rs::{
-    abi::fuse_abi::{Attr, FsOptions, Opcode, OpenOptions, stat64},
+    abi::fuse_abi::{
+        Attr, CreateIn, FsOptions, Opcode, OpenOptions, SetattrValid, stat64, statvfs64,
+    },
     api::{
-        filesystem::{DirEntry, Entry, FileSystem},
+        filesystem::{DirEntry, Entry, FileSystem, GetxattrReply, ListxattrReply},
         server::{MetricsHook, Server},
     },
     transport::{FuseChannel, FuseSession},
 };
 use log::{debug, error, info, trace};
 
+use crate::{
+    error::Error,
+    file_data::FileData,
+    journal::{Journal, Transaction},
+    notify::Notifier,
+};
+
+mod error;
+mod file_data;
+mod image;
+mod journal;
+mod notify;
+
 /// The datamodel for the my-fuse filesystem
 struct MyFileSystem<'a> {
     /// This vector maps index to inode.
     /// The index 0 is therefore the root node of the filesystem
     nodes: RwLock<Vec<Option<Arc<RwLock<Node>>>>>,
 
-    /// This queue contains the inodes that can be used again.
+    /// This queue contains the inodes that can be used again together with the generation of their next node.
     /// The nodes vector should have a None value in these places.
-    reusable_inode_queue: RwLock<LinkedList<Inode>>,
+    reusable_inode_queue: RwLock<LinkedList<(Inode, u64)>>,
 
     /// This BTree mapps absolute file paths to nodes and is an index for fast path lookups.
     /// A possible key could be "/path/to/a/file.txt". The root "/" is relative to the filesystem mount point.
+    #[allow(dead_code)]
     path_index: BTreeMap<&'a str, Weak<Arc<Node>>>,
+
+    /// This BTree maps every handle handed out by open, create or opendir to its state.
+    /// Handles are removed again on release or releasedir.
+    open_handles: RwLock<BTreeMap<Handle, OpenHandle>>,
+
+    /// The handle that is handed out by the next open
+    next_handle: AtomicU64,
+
+    /// The number of bytes the content of all files takes up
+    used_bytes: AtomicU64,
+
+    /// The number of nodes that are currently alive
+    used_inodes: AtomicU64,
+
+    /// The most bytes the content of all files may take up. There is no limit if this is None.
+    max_bytes: Option<u64>,
+
+    /// The most nodes that may be alive at the same time. There is no limit if this is None.
+    max_inodes: Option<u64>,
+
+    /// Every operation that changes nodes holds this lock shared.
+    /// Holding it exclusively therefore gives a consistent view of the whole tree.
+    snapshot_lock: RwLock<()>,
+
+    /// Only one image is saved at a time. It is taken before every other lock.
+    save_lock: Mutex<()>,
+
+    /// Renames between two folders hold this lock, so the parents of folders can not change
+    /// while such a rename checks for loops and decides in which order it locks the folders.
+    rename_lock: Mutex<()>,
+
+    /// Every change is recorded here until it is saved to the image. There is none without an image.
+    journal: Option<Journal>,
+
+    /// How long the kernel may cache what the filesystem tells it
+    timeouts: CacheTimeouts,
+
+    /// Tells the kernel about changes it did not make itself. There is none without a mounted session.
+    notifier: Option<Notifier>,
 }
 
 impl<'a> MyFileSystem<'a> {
-    pub fn new() -> MyFileSystem<'a> {
+    pub fn new(options: &MountOptions) -> MyFileSystem<'a> {
         MyFileSystem {
             path_index: BTreeMap::new(),
             nodes: RwLock::new(Vec::new()),
             reusable_inode_queue: RwLock::new(LinkedList::new()),
+            open_handles: RwLock::new(BTreeMap::new()),
+            next_handle: AtomicU64::new(1),
+            used_bytes: AtomicU64::new(0),
+            used_inodes: AtomicU64::new(0),
+            max_bytes: options.max_bytes,
+            max_inodes: options.max_inodes,
+            snapshot_lock: RwLock::new(()),
+            save_lock: Mutex::new(()),
+            rename_lock: Mutex::new(()),
+            journal: None,
+            timeouts: options.timeouts,
+            notifier: None,
+        }
+    }
+
+    /// Loads the image and replays its journal on top of it.
+    /// The journal is compacted into the image if it contained any changes.
+    fn restore(&mut self, image: &Path, sync: SyncPolicy) -> io::Result<()> {
+        let mut contents = if image.exists() {
+            image::read(image)?
+        } else {
+            image::Image::default()
+        };
+        let mut journal_id = contents.journal_id;
+        let journal_path = journal::path(image);
+        let replayed = journal::replay(&journal_path, journal_id, &mut contents.nodes)?;
+        if !contents.nodes.is_empty() {
+            image::install(self, contents)?;
         }
+        if replayed > 0 {
+            // The current journal may already continue the previous one with the next id, so it is skipped as well
+            journal_id += 2;
+            image::write(image, journal_id, &image::snapshot(self)?)?;
+        }
+        // The previous journal is in the image now or it had no changes
+        journal::remove_previous(&journal_path)?;
+        self.journal = Some(Journal::open(&journal_path, journal_id, sync)?);
+        Ok(())
     }
 }
 
@@ -46,892 +145,4993 @@ impl<'a> MyFileSystem<'a> {
 #[derive(Debug)]
 struct Node {
     inode: Inode,
+    /// The number of directory entries referring to this node.
+    /// Folders count their own entry, their "." entry and the ".." entry of every child folder.
+    nlink: u32,
+    /// Counts how often the inode was used by a node before.
+    /// The inode together with the generation identifies this node for the whole lifetime of the filesystem.
+    generation: u64,
+    /// The number of times this node was handed to the kernel through an entry.
+    /// The kernel gives these references back with forget.
+    lookups: AtomicU64,
+    /// The number of handles that are currently open for this node
+    open_handles: u32,
+    uid: u32,
+    gid: u32,
+    /// The permission bits of this node. The file type is given by the inner node.
+    mode: u32,
+    /// The time of the last access to the content
+    atime: SystemTime,
+    /// The time of the last change to the content
+    mtime: SystemTime,
+    /// The time of the last change to the content or metadata
+    ctime: SystemTime,
+    /// The time this node was created.
+    /// FUSE on Linux has no way to report it, but it is kept for other consumers.
+    crtime: SystemTime,
+    /// This BTree maps the full name of an extended attribute like "user.hash" to its value
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
     inner: InnerNode,
 }
 
 impl Node {
-    fn new_folder(inode: Inode) -> Self {
+    fn new(inode: Inode, nlink: u32, uid: u32, gid: u32, mode: u32, inner: InnerNode) -> Self {
+        let now = SystemTime::now();
         Self {
             inode,
-            inner: InnerNode::Folder(Folder {
-                entries: BTreeMap::new(),
-            }),
+            nlink,
+            generation: 0,
+            lookups: AtomicU64::new(0),
+            open_handles: 0,
+            uid,
+            gid,
+            mode: mode & PERMISSION_BITS,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            xattrs: BTreeMap::new(),
+            inner,
         }
     }
 
-    fn new_file(inode: Inode) -> Self {
-        Self {
+    fn new_folder(inode: Inode, parent: Inode, uid: u32, gid: u32, mode: u32) -> Self {
+        Self::new(
+            inode,
+            2,
+            uid,
+            gid,
+            mode,
+            InnerNode::Folder(Folder::new(parent)),
+        )
+    }
+
+    fn new_file(inode: Inode, uid: u32, gid: u32, mode: u32) -> Self {
+        Self::new(
             inode,
-            inner: InnerNode::File(File {
-                data: Arc::new(RwLock::new(vec![])),
+            1,
+            uid,
+            gid,
+            mode,
+            InnerNode::File(File {
+                data: Arc::new(RwLock::new(FileData::default())),
             }),
+        )
+    }
+
+    fn new_symlink(inode: Inode, uid: u32, gid: u32, target: Vec<u8>) -> Self {
+        // The permissions of symlinks are never checked, so they always report rwxrwxrwx
+        Self::new(
+            inode,
+            1,
+            uid,
+            gid,
+            0o777,
+            InnerNode::Symlink(Symlink { target }),
+        )
+    }
+
+    fn new_special(inode: Inode, uid: u32, gid: u32, mode: u32, file_type: u32, rdev: u32) -> Self {
+        Self::new(
+            inode,
+            1,
+            uid,
+            gid,
+            mode,
+            InnerNode::Special(Special { file_type, rdev }),
+        )
+    }
+
+    /// The owner of a new node created inside this folder by the caller of the request.
+    /// Like on other unix filesystems the group is inherited from a parent with the set-group-ID bit.
+    fn child_owner(&self, ctx: &fuse_backend_rs::api::filesystem::Context) -> (u32, u32) {
+        if self.mode & libc::S_ISGID != 0 {
+            (ctx.uid, self.gid)
+        } else {
+            (ctx.uid, ctx.gid)
         }
     }
 
-    fn get_entry(&self) -> Entry {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    /// The folder this node is or ENOTDIR
+    fn folder(&self) -> Result<&Folder, Error> {
+        match &self.inner {
+            InnerNode::Folder(folder) => Ok(folder),
+            _ => Err(Error::NotADirectory(self.inode)),
+        }
+    }
 
-        let attr = match &self.inner {
-            InnerNode::File(file) => {
-                let size = file.data.read().unwrap().len();
-                Attr {
-                    ino: self.inode,
-                    mode: libc::S_IFREG | libc::S_IRWXU | libc::S_IRGRP | libc::S_IROTH,
-                    uid: 1000,
-                    gid: 100,
-                    size: size as u64,
-                    blksize: 1u32,
-                    blocks: size as u64,
-                    atime: now,
-                    mtime: now,
-                    ctime: now,
-                    ..Default::default()
-                }
-            }
-            InnerNode::Folder(folder) => Attr {
-                ino: self.inode,
-                mode: libc::S_IFDIR | libc::S_IRWXU | libc::S_IRGRP | libc::S_IROTH,
-                uid: 1000,
-                gid: 100,
-                size: folder.entries.len() as u64,
-                blksize: 1u32,
-                blocks: folder.entries.len() as u64,
-                atime: now,
-                mtime: now,
-                ctime: now,
-                ..Default::default()
+    /// The folder this node is or ENOTDIR
+    fn folder_mut(&mut self) -> Result<&mut Folder, Error> {
+        let inode = self.inode;
+        match &mut self.inner {
+            InnerNode::Folder(folder) => Ok(folder),
+            _ => Err(Error::NotADirectory(inode)),
+        }
+    }
+
+    /// The inode the entry `name` of this folder points to
+    fn entry(&self, name: &OsStr) -> Result<Inode, Error> {
+        self.folder()?
+            .get(name)
+            .ok_or_else(|| Error::EntryNotFound(self.inode, name.to_owned()))
+    }
+
+    /// A copy of the attributes, so changes can be undone if the journal can not be written
+    fn attributes(&self) -> Attributes {
+        Attributes {
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            mode: self.mode,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+        }
+    }
+
+    /// Puts back the attributes of an earlier copy
+    fn restore_attributes(&mut self, attributes: Attributes) {
+        let Attributes {
+            nlink,
+            uid,
+            gid,
+            mode,
+            atime,
+            mtime,
+            ctime,
+        } = attributes;
+        (self.nlink, self.uid, self.gid, self.mode) = (nlink, uid, gid, mode);
+        (self.atime, self.mtime, self.ctime) = (atime, mtime, ctime);
+    }
+
+    /// Marks the content of this node as changed
+    fn update_mtime(&mut self) {
+        let now = SystemTime::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+
+    /// Marks the metadata of this node as changed
+    fn update_ctime(&mut self) {
+        self.ctime = SystemTime::now();
+    }
+
+    /// Access times are only updated like with the relatime mount option.
+    /// This is when the content changed since the last access or the last access is a day old.
+    fn atime_outdated(&self) -> bool {
+        self.atime <= self.mtime
+            || self.atime <= self.ctime
+            || self
+                .atime
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= ATIME_UPDATE_INTERVAL)
+    }
+
+    /// Marks the content of this node as accessed
+    fn update_atime(&mut self) {
+        if self.atime_outdated() {
+            self.atime = SystemTime::now();
+        }
+    }
+
+    /// The type of this node like readdir reports it
+    fn dirent_type(&self) -> u32 {
+        let dirent_type = match &self.inner {
+            InnerNode::File(_) => libc::DT_REG,
+            InnerNode::Folder(_) => libc::DT_DIR,
+            InnerNode::Symlink(_) => libc::DT_LNK,
+            InnerNode::Special(special) => match special.file_type {
+                libc::S_IFCHR => libc::DT_CHR,
+                libc::S_IFBLK => libc::DT_BLK,
+                libc::S_IFIFO => libc::DT_FIFO,
+                _ => libc::DT_SOCK,
             },
         };
+        dirent_type as u32
+    }
+
+    /// The number of bytes the content of this node takes up in the byte budget
+    fn content_size(&self) -> u64 {
+        match &self.inner {
+            InnerNode::File(file) => file.data.read().unpoisoned().allocated(),
+            _ => 0,
+        }
+    }
+
+    /// A node can only be destroyed when it is neither reachable by name
+    /// nor referenced by the kernel or an open handle.
+    fn can_reclaim(&self) -> bool {
+        self.nlink == 0 && self.lookups.load(Ordering::Acquire) == 0 && self.open_handles == 0
+    }
+
+    /// Returns the entry and counts the reference the kernel keeps to it until it is forgotten
+    fn lookup_entry(&self, timeouts: &CacheTimeouts) -> Entry {
+        self.lookups.fetch_add(1, Ordering::AcqRel);
+        self.get_entry(timeouts)
+    }
+
+    fn get_entry(&self, timeouts: &CacheTimeouts) -> Entry {
+        let (file_type, size) = match &self.inner {
+            InnerNode::File(file) => (libc::S_IFREG, file.data.read().unpoisoned().len()),
+            InnerNode::Folder(folder) => (libc::S_IFDIR, folder.len() as u64),
+            InnerNode::Symlink(symlink) => (libc::S_IFLNK, symlink.target.len() as u64),
+            InnerNode::Special(special) => (special.file_type, 0),
+        };
+        let (atime, atimensec) = to_unix_time(self.atime);
+        let (mtime, mtimensec) = to_unix_time(self.mtime);
+        let (ctime, ctimensec) = to_unix_time(self.ctime);
+
+        // Times before the epoch are negative and passed to the kernel in two's complement
+        let attr = Attr {
+            ino: self.inode,
+            nlink: self.nlink,
+            mode: file_type | self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            size,
+            blksize: 1u32,
+            // The kernel expects the allocated space in units of 512 bytes
+            blocks: self.content_size().div_ceil(512),
+            atime: atime as u64,
+            mtime: mtime as u64,
+            ctime: ctime as u64,
+            atimensec,
+            mtimensec,
+            ctimensec,
+            ..Default::default()
+        };
 
         Entry {
             inode: self.inode,
-            generation: 0,
+            generation: self.generation,
             attr: attr.into(),
             attr_flags: 0,
-            attr_timeout: Duration::from_secs(1 << 32),
-            entry_timeout: Duration::from_secs(1 << 32),
+            attr_timeout: timeouts.attr,
+            entry_timeout: timeouts.entry,
+        }
+    }
+}
+
+/// Splits a time into the seconds and nanoseconds since the unix epoch like stat reports them
+fn to_unix_time(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+        Err(e) => {
+            // The nanoseconds always count forward, even before the epoch
+            let duration = e.duration();
+            match duration.subsec_nanos() {
+                0 => (-(duration.as_secs() as i64), 0),
+                nanos => (-(duration.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
         }
     }
 }
 
+/// The amount of physical memory of the host in bytes
+fn total_memory() -> u64 {
+    // SAFETY: sysinfo only writes into the given struct, for which zero is a valid value
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sysinfo(&mut info) } == 0 {
+        info.totalram as u64 * info.mem_unit as u64
+    } else {
+        0
+    }
+}
+
+/// The inverse of [to_unix_time]. Returns None for times a SystemTime can not hold.
+fn from_unix_time(secs: i64, nanos: i64) -> Option<SystemTime> {
+    let nanos = Duration::from_nanos(nanos.clamp(0, 999_999_999) as u64);
+    if secs >= 0 {
+        UNIX_EPOCH
+            .checked_add(Duration::from_secs(secs as u64))?
+            .checked_add(nanos)
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(secs.unsigned_abs()))?
+            .checked_add(nanos)
+    }
+}
+
+/// The name of a directory entry as given by the kernel.
+/// Any bytes but "/" and NUL are allowed, so names do not have to be UTF-8.
+fn entry_name(name: &CStr) -> Result<&OsStr, Error> {
+    let name = name.to_bytes();
+    if name.len() > MAX_NAME_SIZE {
+        return Err(Error::NameTooLong);
+    }
+    // A C string can not contain NUL, so only the separator is left to check
+    if name.is_empty() || name.contains(&b'/') {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(OsStr::from_bytes(name))
+}
+
+/// Taking a lock that a panicking thread held before still works.
+/// Requests do not panic, so this only keeps a bug in one request from taking down every later one.
+trait Unpoisoned<T> {
+    fn unpoisoned(self) -> T;
+}
+
+impl<T> Unpoisoned<T> for LockResult<T> {
+    fn unpoisoned(self) -> T {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The attributes of a node that requests change, which are also the ones the journal records together
+#[derive(Clone, Copy, Debug)]
+struct Attributes {
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+}
+
 #[derive(Debug)]
 enum InnerNode {
     File(File),
     Folder(Folder),
+    Symlink(Symlink),
+    Special(Special),
 }
 
 #[derive(Clone, Debug)]
 struct File {
-    pub data: Arc<RwLock<Vec<u8>>>,
+    pub data: Arc<RwLock<FileData>>,
 }
 
 #[derive(Debug)]
 struct Folder {
-    /// This BTree mapps a path segment to a child inode of this folder
-    entries: BTreeMap<String, Inode>,
+    /// This BTree mapps a path segment to a child inode of this folder and the cookie of the entry.
+    /// Names are raw bytes like on every other Linux filesystem and not necessarily UTF-8.
+    entries: BTreeMap<OsString, (Inode, u64)>,
+
+    /// This BTree orders the names by the cookie of their entry, which is the order readdir lists them in.
+    /// Cookies only grow, so a listing continues at the right entry while others are added or removed.
+    listing: BTreeMap<u64, OsString>,
+
+    /// The cookie of the next new entry
+    next_cookie: u64,
+
+    /// The folder that has an entry for this folder. The root is its own parent.
+    parent: Inode,
 }
 
-impl MyFileSystem<'_> {
-    fn load(&self, inode: Inode) -> io::Result<Arc<RwLock<Node>>> {
-        let nodes = self.nodes.read().unwrap();
-        if let Some(node) = &nodes[inode as usize - 1] {
-            let arc = node.clone();
-            Ok(arc)
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Inode not found: {inode}"),
-            ))
+impl Folder {
+    fn new(parent: Inode) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            listing: BTreeMap::new(),
+            next_cookie: FIRST_ENTRY_COOKIE,
+            parent,
         }
     }
 
-    fn next_inode(&self) -> Inode {
-        if let Some(inode) = self.reusable_inode_queue.write().unwrap().pop_back() {
-            inode
-        } else {
-            let mut nodes = self.nodes.write().unwrap();
-            nodes.push(None);
-            nodes.len() as Inode // This should return the last index + 1 (inode 0 is invalid). Now a None value
+    /// The inode the entry `name` points to
+    fn get(&self, name: &OsStr) -> Option<Inode> {
+        self.entries.get(name).map(|&(inode, _)| inode)
+    }
+
+    fn contains(&self, name: &OsStr) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds the entry `name` or points it to another inode. A replaced entry keeps its place in listings.
+    fn insert(&mut self, name: &OsStr, inode: Inode) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            entry.0 = inode;
+            return;
+        }
+        let cookie = self.next_cookie;
+        self.next_cookie += 1;
+        self.entries.insert(name.to_owned(), (inode, cookie));
+        self.listing.insert(cookie, name.to_owned());
+    }
+
+    /// Removes the entry `name` and returns the inode it pointed to
+    fn remove(&mut self, name: &OsStr) -> Option<Inode> {
+        let (inode, cookie) = self.entries.remove(name)?;
+        self.listing.remove(&cookie);
+        Some(inode)
+    }
+
+    /// The inode and cookie of the entry `name`, so it can be put back with `restore`
+    fn saved(&self, name: &OsStr) -> Option<(Inode, u64)> {
+        self.entries.get(name).copied()
+    }
+
+    /// Puts the entry `name` back like `saved` returned it, at its old place in listings
+    fn restore(&mut self, name: &OsStr, saved: Option<(Inode, u64)>) {
+        self.remove(name);
+        if let Some((inode, cookie)) = saved {
+            self.entries.insert(name.to_owned(), (inode, cookie));
+            self.listing.insert(cookie, name.to_owned());
         }
     }
+
+    /// Removes all entries and returns the inodes they pointed to
+    fn take_inodes(&mut self) -> Vec<Inode> {
+        self.listing.clear();
+        std::mem::take(&mut self.entries)
+            .into_values()
+            .map(|(inode, _)| inode)
+            .collect()
+    }
+
+    /// All entries ordered by name
+    fn iter(&self) -> impl Iterator<Item = (&OsStr, Inode)> {
+        self.entries
+            .iter()
+            .map(|(name, &(inode, _))| (name.as_os_str(), inode))
+    }
+
+    /// The entries readdir lists after the one with `cookie` together with their own cookies
+    fn list_after(&self, cookie: u64) -> impl Iterator<Item = (u64, &OsStr, Inode)> {
+        self.listing
+            .range(cookie.saturating_add(1)..)
+            .map(|(&cookie, name)| (cookie, name.as_os_str(), self.entries[name].0))
+    }
 }
 
-const MAX_FILE_SIZE: usize = 4294967296; // 4GiB / 4.29 GB
-const BLOCK_SIZE: usize = 4096;
+#[derive(Debug)]
+struct Symlink {
+    /// The path this link points to. It is stored as given and never resolved by the filesystem
+    target: Vec<u8>,
+}
 
-type Inode = u64;
-type Handle = u64;
+/// The state of a handle handed out by open, create or opendir
+#[derive(Clone, Copy, Debug)]
+struct OpenHandle {
+    /// The node the handle was opened for
+    inode: Inode,
 
-impl FileSystem for MyFileSystem<'_> {
-    type Inode = Inode;
-    type Handle = Handle;
+    /// Writes through this handle always go to the end of the file (O_APPEND)
+    append: bool,
+}
 
-    fn init(&self, capable: FsOptions) -> std::io::Result<FsOptions> {
-        let _ = capable; // unused
-        let root_node = Node::new_folder(1);
-        let mut nodes = self.nodes.write().unwrap();
-        nodes.push(Some(Arc::new(RwLock::new(root_node))));
-        info!("Filesystem Init");
-        Ok(FsOptions::ASYNC_READ
-            | FsOptions::BIG_WRITES
-            | FsOptions::ASYNC_DIO
-            | FsOptions::PARALLEL_DIROPS
-            | FsOptions::ZERO_MESSAGE_OPEN
-            | FsOptions::ZERO_MESSAGE_OPENDIR)
+/// A device node, FIFO or socket. The filesystem only stores them, the kernel implements them.
+#[derive(Debug)]
+struct Special {
+    /// One of [SPECIAL_FILE_TYPES]
+    file_type: u32,
+
+    /// The device number of a character or block device
+    rdev: u32,
+}
+
+impl MyFileSystem<'_> {
+    /// Starts recording the changes of an operation for the journal
+    fn transaction(&self) -> Transaction {
+        Transaction::new(self.journal.is_some())
     }
 
-    fn lookup(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        parent: Self::Inode,
-        name: &CStr,
-    ) -> io::Result<Entry> {
-        let _ = ctx;
-        debug!("Lookup parent={parent} name={}", name.to_str().unwrap());
-
-        self.load(parent).and_then(|e| {
-            let node = &*e.read().unwrap();
-            match &node.inner {
-                InnerNode::Folder(folder) => {
-                    if let Some(inode) = folder.entries.get(name.to_str().unwrap()) {
-                        let rw_lock = self.load(*inode)?;
-                        let entry = rw_lock.read().unwrap();
-                        Ok(entry.get_entry())
-                    } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::NotFound,
-                            format!("File or folder not found: {parent} {name:?}"),
-                        ))
-                    }
-                }
-                _ => Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("Parent not found for lookup: {parent}"),
-                )),
-            }
-        })
+    /// Appends the changes of an operation to the journal.
+    /// The changed nodes have to be still locked, so the journal has the same order as the changes.
+    fn commit(&self, transaction: Transaction) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.append(transaction),
+            None => Ok(()),
+        }
     }
 
-    fn getattr(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        inode: Self::Inode,
-        handle: Option<Self::Handle>,
-    ) -> io::Result<(stat64, Duration)> {
-        let _ = ctx;
-        debug!("Getting attributes for inode {inode} and handle {handle:?}");
+    /// Returns the node with this inode. Inode 0 and inodes past the end of the table do not exist.
+    fn load(&self, inode: Inode) -> Result<Arc<RwLock<Node>>, Error> {
+        let nodes = self.nodes.read().unpoisoned();
+        let index = usize::try_from(inode)
+            .ok()
+            .and_then(|inode| inode.checked_sub(1));
+        match index.and_then(|index| nodes.get(index)) {
+            Some(Some(node)) => Ok(node.clone()),
+            _ => Err(Error::InodeNotFound(inode)),
+        }
+    }
 
-        self.load(inode)
-            .map(|e| e.read().unwrap().get_entry())
-            .map(|e| (e.attr, Duration::from_secs(1 << 32)))
+    /// Removes one directory entry pointing to this node
+    fn drop_link(node: &mut Node) {
+        match node.inner {
+            // A removed folder loses its own entry and its "." entry at once
+            InnerNode::Folder(_) => node.nlink = 0,
+            _ => node.nlink = node.nlink.saturating_sub(1),
+        }
     }
 
-    fn setattr(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        inode: Self::Inode,
-        attr: stat64,
-        handle: Option<Self::Handle>,
-        valid: fuse_backend_rs::abi::fuse_abi::SetattrValid,
-    ) -> io::Result<(stat64, Duration)> {
-        let _ = valid;
-        let _ = handle;
-        let _ = ctx;
-        debug!("setattr {attr:#?}");
-        // The attributes are readonly so lets return just the attributes
-        self.load(inode)
-            .map(|e| {
-                let node = e.write().unwrap();
-                match &node.inner {
-                    InnerNode::File(file) => {
-                        // Truncate the file
-                        let mut data = file.data.write().unwrap();
-                        let target_size = attr.st_size as usize;
-                        data.resize(target_size, 0);
-                        data.shrink_to_fit();
-                    }
-                    InnerNode::Folder(_) => {}
-                }
-                node.get_entry()
-            })
-            .map(|e| (e.attr, Duration::from_secs(1 << 32)))
+    /// Drops the link of the locked `child` whose entry was just removed from the folder `parent`.
+    /// Once the change is committed, `release_unlinked` frees what the child does not need anymore.
+    fn drop_entry_link(parent: &mut Node, child: &mut Node, transaction: &mut Transaction) {
+        if let InnerNode::Folder(_) = child.inner {
+            // The ".." entry of the removed folder pointed to the parent
+            parent.nlink = parent.nlink.saturating_sub(1);
+        }
+        parent.update_mtime();
+        Self::drop_link(child);
+        child.update_ctime();
+        transaction.attr(parent);
+        transaction.attr(child);
     }
 
-    /////////////////////////////
-    // Directory Operations
-    /////////////////////////////
+    /// Frees the content of the locked `child` after its last entry was removed.
+    /// The node is reclaimed once nothing refers to it anymore.
+    /// Otherwise the last forget or release reclaims it.
+    fn release_unlinked(&self, child: &mut Node) {
+        self.discard_unreachable_content(child);
+        if child.can_reclaim() {
+            self.reclaim_inode(child);
+        }
+    }
 
-    fn mkdir(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        parent: Self::Inode,
-        name: &CStr,
-        mode: u32,
-        umask: u32,
-    ) -> io::Result<Entry> {
-        debug!("mkdir {parent} {name:?}");
+    /// Removes the entry `name` of the folder `parent` for rmdir or unlink.
+    /// rmdir only removes empty folders and unlink everything else.
+    fn remove_entry(&self, parent: Inode, name: &CStr, folder: bool) -> Result<(), Error> {
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
         let parent = self.load(parent)?;
-        let mut parent = parent.write().unwrap();
-        match &mut parent.inner {
-            InnerNode::File(_) => Err(io::Error::new(
-                io::ErrorKind::NotADirectory,
-                format!("Can not create folder inside file {parent:?}"),
-            )),
-            InnerNode::Folder(folder) => {
-                let inode = self.next_inode();
-                let new_folder = Node::new_folder(inode);
-                debug!("created node {new_folder:#?}");
-                let entry = new_folder.get_entry();
-                let mut nodes = self.nodes.write().unwrap();
-                nodes[inode as usize - 1] = Some(Arc::new(RwLock::new(new_folder)));
-                folder
-                    .entries
-                    .insert(name.to_str().unwrap().to_string(), inode);
-
-                Ok(entry)
+        let mut parent = parent.write().unpoisoned();
+        let name = entry_name(name)?;
+        let inode = parent.entry(name)?;
+        let child = self.load(inode)?;
+        let mut child = child.write().unpoisoned();
+        match (&child.inner, folder) {
+            (InnerNode::Folder(child_folder), true) if !child_folder.is_empty() => {
+                return Err(Error::NotEmpty(inode));
             }
+            (InnerNode::Folder(_), true) => {}
+            (_, true) => return Err(Error::NotADirectory(inode)),
+            (InnerNode::Folder(_), false) => return Err(Error::IsADirectory(inode)),
+            (_, false) => {}
         }
+        let saved = (
+            parent.folder()?.saved(name),
+            parent.attributes(),
+            child.attributes(),
+        );
+        parent.folder_mut()?.remove(name);
+        let mut transaction = self.transaction();
+        transaction.unlink(parent.inode, name);
+        Self::drop_entry_link(&mut parent, &mut child, &mut transaction);
+        if let Err(e) = self.commit(transaction) {
+            let (entry, parent_attributes, child_attributes) = saved;
+            parent.folder_mut()?.restore(name, entry);
+            parent.restore_attributes(parent_attributes);
+            child.restore_attributes(child_attributes);
+            return Err(e.into());
+        }
+        self.release_unlinked(&mut child);
+        Ok(())
     }
 
-    fn rmdir(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        parent: Self::Inode,
-        name: &CStr,
-    ) -> io::Result<()> {
-        debug!("rmdir parent={parent} name={name:?}");
-        let parent = self.load(parent)?;
-        let mut parent = parent.write().unwrap();
-        match &mut parent.inner {
-            InnerNode::File(_) => Err(io::Error::new(
-                io::ErrorKind::NotADirectory,
-                format!("Can not remove folder inside file {parent:?}"),
-            )),
-            InnerNode::Folder(folder) => {
-                if let Some(inode) = folder.entries.remove(name.to_str().unwrap()) {
-                    drop(parent);
-                    let mut nodes = self.nodes.write().unwrap();
-                    nodes[inode as usize - 1] = None;
-                    let mut queue = self.reusable_inode_queue.write().unwrap();
-                    queue.push_back(inode);
-                    debug!("Reusable inode queue {queue:?}");
-                    Ok(())
-                } else {
-                    Err(io::Error::new(
-                        io::ErrorKind::NotFound,
-                        format!("Folder not found inside {parent:?}"),
-                    ))
-                }
+    /// Whether `inode` is the folder `ancestor` or somewhere below it.
+    /// Only renames between folders change parents, so the caller has to hold the rename lock.
+    fn is_ancestor(&self, ancestor: Inode, mut inode: Inode) -> Result<bool, Error> {
+        while inode != ancestor {
+            if inode == ROOT_INODE {
+                return Ok(false);
             }
+            let node = self.load(inode)?;
+            inode = match &node.read().unpoisoned().inner {
+                InnerNode::Folder(folder) => folder.parent,
+                _ => return Ok(false),
+            };
         }
+        Ok(true)
     }
 
-    fn readdir(
+    /// The folder a rename moves the entry into. It is `old_dir` for a rename inside one folder.
+    fn rename_new_dir<'b>(
+        old_dir: &'b mut Node,
+        new_dir: &'b mut Option<&mut Node>,
+    ) -> &'b mut Node {
+        match new_dir {
+            Some(new_dir) => new_dir,
+            None => old_dir,
+        }
+    }
+
+    /// Renames the entry `oldname` of `old_dir` to `newname` of `new_dir` with both folders locked.
+    /// `new_dir` is None for a rename inside one folder.
+    /// The caller has to rule out that a folder is moved below itself or an ancestor of `old_dir` is replaced.
+    fn rename_locked(
         &self,
         ctx: &fuse_backend_rs::api::filesystem::Context,
-        inode: Self::Inode,
-        handle: Self::Handle,
-        size: u32,
-        offset: u64,
-        add_entry: &mut dyn FnMut(fuse_backend_rs::api::filesystem::DirEntry) -> io::Result<usize>,
-    ) -> io::Result<()> {
-        let _ = handle; // unused
-        let _ = size;
-        debug!("Reading directory {} with offset {offset}", inode);
-
-        let node = self.load(inode)?;
+        old_dir: &mut Node,
+        mut new_dir: Option<&mut Node>,
+        oldname: &OsStr,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), Error> {
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        let (olddir, newdir) = (
+            old_dir.inode,
+            Self::rename_new_dir(old_dir, &mut new_dir).inode,
+        );
+        let moved_inode = old_dir.entry(oldname)?;
+        let target_inode = Self::rename_new_dir(old_dir, &mut new_dir)
+            .folder()?
+            .get(newname);
+        if flags & libc::RENAME_NOREPLACE != 0 && target_inode.is_some() {
+            return Err(Error::AlreadyExists(newdir, newname.to_owned()));
+        }
+        if exchange && target_inode.is_none() {
+            return Err(Error::EntryNotFound(newdir, newname.to_owned()));
+        }
+        // Both names are links to the same node already, so there is nothing to do
+        if target_inode == Some(moved_inode) {
+            return Ok(());
+        }
 
-        let node1 = &*node.read().unwrap();
-        match &node1.inner {
-            InnerNode::Folder(folder) => {
-                for (i, (name, child_inode)) in folder
-                    .entries
-                    .iter()
-                    .skip(offset as usize)
-                    .take(size as usize)
-                    .enumerate()
-                {
-                    let child_node = self.load(*child_inode)?;
-                    let entry_type = match &child_node.read().unwrap().inner {
-                        InnerNode::File(_) => libc::DT_REG,
-                        InnerNode::Folder(_) => libc::DT_DIR,
-                    };
-                    add_entry(DirEntry {
-                        ino: *child_inode,
-                        offset: i as u64 + 1,
-                        type_: entry_type as u32,
-                        name: name.as_bytes(),
-                    })?;
+        // The children are unrelated to each other, so they are locked in inode order
+        let moved = self.load(moved_inode)?;
+        let target = target_inode.map(|inode| self.load(inode)).transpose()?;
+        let (mut moved, mut target) = match &target {
+            Some(target) if target_inode < Some(moved_inode) => {
+                let target = target.write().unpoisoned();
+                (moved.write().unpoisoned(), Some(target))
+            }
+            _ => (
+                moved.write().unpoisoned(),
+                target.as_ref().map(|target| target.write().unpoisoned()),
+            ),
+        };
+        let moved_is_folder = matches!(moved.inner, InnerNode::Folder(_));
+        if !exchange && let Some(target) = &target {
+            match (moved_is_folder, &target.inner) {
+                (true, InnerNode::Folder(folder)) if !folder.is_empty() => {
+                    return Err(Error::NotEmpty(target.inode));
                 }
-                Ok(())
+                (true, InnerNode::Folder(_)) => {}
+                (true, _) => return Err(Error::NotADirectory(target.inode)),
+                (false, InnerNode::Folder(_)) => return Err(Error::IsADirectory(target.inode)),
+                (false, _) => {}
             }
-            _ => Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Readdir inode not found: {inode}"),
-            )),
         }
-    }
+        // The whiteout is created first, because it is the only part that can fail before the commit
+        let whiteout = if flags & libc::RENAME_WHITEOUT != 0 {
+            let (inode, generation) = self.next_inode()?;
+            let (uid, gid) = old_dir.child_owner(ctx);
+            let mut whiteout = Node::new_special(inode, uid, gid, 0, libc::S_IFCHR, 0);
+            whiteout.generation = generation;
+            Some(whiteout)
+        } else {
+            None
+        };
 
-    /////////////////////////
-    // File Operations
-    /////////////////////////
+        // Everything is saved, so the rename can be undone if the journal can not be written
+        let old_entry = old_dir.folder()?.saved(oldname);
+        let new_entry = Self::rename_new_dir(old_dir, &mut new_dir)
+            .folder()?
+            .saved(newname);
+        let old_dir_attributes = old_dir.attributes();
+        let new_dir_attributes = new_dir.as_ref().map(|new_dir| new_dir.attributes());
+        let moved_attributes = moved.attributes();
+        let target_attributes = target.as_ref().map(|target| target.attributes());
+        let whiteout_inode = whiteout
+            .as_ref()
+            .map(|whiteout| (whiteout.inode, whiteout.generation));
 
-    fn mknod(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        inode: Self::Inode,
-        name: &CStr,
-        mode: u32,
-        rdev: u32,
-        umask: u32,
-    ) -> io::Result<Entry> {
-        debug!("mknod {inode} {name:?}");
-        let parent = self.load(inode)?;
-        let mut parent = parent.write().unwrap();
-        match &mut parent.inner {
-            InnerNode::File(_) => Err(io::Error::new(
-                io::ErrorKind::NotADirectory,
-                format!("Can not create file inside file {parent:?}"),
-            )),
-            InnerNode::Folder(folder) => {
-                let new_inode = self.next_inode();
-                folder
-                    .entries
-                    .insert(name.to_str().unwrap().to_string(), new_inode);
-
-                drop(parent);
-
-                let new_file = Node::new_file(new_inode);
-                debug!("created file {new_file:#?}");
-                let entry = new_file.get_entry();
-                let mut nodes = self.nodes.write().unwrap();
-                nodes[new_inode as usize - 1] = Some(Arc::new(RwLock::new(new_file)));
+        let mut transaction = self.transaction();
+        let new_folder = Self::rename_new_dir(old_dir, &mut new_dir).folder_mut()?;
+        new_folder.insert(newname, moved_inode);
+        transaction.link(newdir, newname, moved_inode);
+        if exchange {
+            old_dir
+                .folder_mut()?
+                .insert(oldname, target_inode.unwrap_or(moved_inode));
+            transaction.link(olddir, oldname, target_inode.unwrap_or(moved_inode));
+        } else {
+            old_dir.folder_mut()?.remove(oldname);
+            transaction.unlink(olddir, oldname);
+            if let Some(target) = &mut target {
+                let new_dir = Self::rename_new_dir(old_dir, &mut new_dir);
+                Self::drop_entry_link(new_dir, target, &mut transaction);
+            }
+        }
+        if let Some(new_dir) = &mut new_dir {
+            // The ".." entry of a moved folder now points to its new parent
+            if let InnerNode::Folder(folder) = &mut moved.inner {
+                folder.parent = newdir;
+                old_dir.nlink = old_dir.nlink.saturating_sub(1);
+                new_dir.nlink += 1;
+            }
+            if exchange
+                && let Some(target) = &mut target
+                && let InnerNode::Folder(folder) = &mut target.inner
+            {
+                folder.parent = olddir;
+                new_dir.nlink = new_dir.nlink.saturating_sub(1);
+                old_dir.nlink += 1;
+            }
+        }
+        if let Some(whiteout) = whiteout {
+            debug!("created whiteout {whiteout:#?}");
+            old_dir.folder_mut()?.insert(oldname, whiteout.inode);
+            transaction.create(&whiteout);
+            transaction.link(olddir, oldname, whiteout.inode);
+            let mut nodes = self.nodes.write().unpoisoned();
+            let inode = whiteout.inode;
+            nodes[inode as usize - 1] = Some(Arc::new(RwLock::new(whiteout)));
+        }
 
-                Ok(entry)
+        moved.update_ctime();
+        transaction.attr(&moved);
+        if exchange && let Some(target) = &mut target {
+            target.update_ctime();
+            transaction.attr(target);
+        }
+        old_dir.update_mtime();
+        transaction.attr(old_dir);
+        if let Some(new_dir) = &mut new_dir {
+            new_dir.update_mtime();
+            transaction.attr(new_dir);
+        }
+        if let Err(e) = self.commit(transaction) {
+            Self::rename_new_dir(old_dir, &mut new_dir)
+                .folder_mut()?
+                .restore(newname, new_entry);
+            old_dir.folder_mut()?.restore(oldname, old_entry);
+            // Moved folders go back below their old parents
+            if let InnerNode::Folder(folder) = &mut moved.inner {
+                folder.parent = olddir;
             }
+            if let Some(target) = &mut target
+                && let InnerNode::Folder(folder) = &mut target.inner
+            {
+                folder.parent = newdir;
+            }
+            old_dir.restore_attributes(old_dir_attributes);
+            if let (Some(new_dir), Some(attributes)) = (&mut new_dir, new_dir_attributes) {
+                new_dir.restore_attributes(attributes);
+            }
+            moved.restore_attributes(moved_attributes);
+            if let (Some(target), Some(attributes)) = (&mut target, target_attributes) {
+                target.restore_attributes(attributes);
+            }
+            if let Some((inode, generation)) = whiteout_inode {
+                self.release_inode(inode, generation);
+            }
+            return Err(e.into());
+        }
+        if !exchange && let Some(target) = &mut target {
+            self.release_unlinked(target);
         }
+        if target_inode.is_none() {
+            self.invalidate_miss(newdir, newname);
+        }
+        Ok(())
     }
 
-    fn unlink(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        parent: Self::Inode,
-        name: &CStr,
-    ) -> io::Result<()> {
-        debug!("unlink parent={parent} name={name:?}");
-        let parent = self.load(parent)?;
-        let mut parent = parent.write().unwrap();
-        match &mut parent.inner {
-            InnerNode::File(_) => Err(io::Error::new(
-                io::ErrorKind::NotADirectory,
-                format!("Can not remove file inside file {parent:?}"),
-            )),
-            InnerNode::Folder(folder) => {
-                if let Some(inode) = folder.entries.remove(name.to_str().unwrap()) {
-                    drop(parent);
-
-                    let mut nodes = self.nodes.write().unwrap();
+    /// Frees the content of a file that can neither be reached by name nor through a handle anymore.
+    /// The node itself lives on until the kernel forgets it, but its space is available right away.
+    fn discard_unreachable_content(&self, node: &Node) {
+        if let InnerNode::File(file) = &node.inner
+            && node.nlink == 0
+            && node.open_handles == 0
+        {
+            let mut data = file.data.write().unpoisoned();
+            self.release_bytes(data.allocated());
+            *data = FileData::default();
+        }
+    }
 
-                    nodes[inode as usize - 1] = None;
-                    let mut queue = self.reusable_inode_queue.write().unwrap();
-                    queue.push_back(inode);
-                    debug!("Reusable inode queue {queue:?}");
-                    Ok(())
-                } else {
-                    Err(io::Error::new(
-                        io::ErrorKind::NotFound,
-                        format!("Folder not found inside {parent:?}"),
-                    ))
+    /// Frees the node slot and makes the inode and the space of its content available for reuse.
+    /// The next node with this inode gets a higher generation, so the two can be told apart.
+    /// The entries of a reclaimed folder are dropped as well, which reclaims everything below it
+    /// that is not referenced otherwise.
+    /// The caller may still hold the lock of the node, which keeps it from being revived meanwhile.
+    fn reclaim_inode(&self, node: &mut Node) {
+        if let InnerNode::Folder(folder) = &mut node.inner {
+            // The children are locked before the node table, like everywhere else
+            for inode in folder.take_inodes() {
+                let Ok(child) = self.load(inode) else {
+                    continue;
+                };
+                let mut child = child.write().unpoisoned();
+                Self::drop_link(&mut child);
+                self.discard_unreachable_content(&child);
+                if child.can_reclaim() {
+                    self.reclaim_inode(&mut child);
                 }
             }
         }
+
+        let inode = node.inode;
+        self.release_bytes(node.content_size());
+        self.used_inodes.fetch_sub(1, Ordering::AcqRel);
+
+        let mut nodes = self.nodes.write().unpoisoned();
+        nodes[inode as usize - 1] = None;
+        let mut queue = self.reusable_inode_queue.write().unpoisoned();
+        queue.push_back((inode, node.generation.wrapping_add(1)));
+        debug!("Reusable inode queue {queue:?}");
     }
 
-    fn rename(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        olddir: Self::Inode,
-        oldname: &CStr,
-        newdir: Self::Inode,
-        newname: &CStr,
-        flags: u32,
-    ) -> io::Result<()> {
-        let old_dir_node = self.load(olddir)?;
-        let old_dir_node = &mut old_dir_node.write().unwrap();
+    /// Returns a free inode and its generation for a new node or ENOSPC if the inode budget is used up
+    fn next_inode(&self) -> Result<(Inode, u64), Error> {
+        Self::reserve(&self.used_inodes, 1, self.max_inodes)?;
 
-        if olddir == newdir {
-            // Would deadlock because the folders are the same
-            match &mut old_dir_node.inner {
-                InnerNode::Folder(folder) => {
-                    if let Some(inode) = folder.entries.remove(oldname.to_str().unwrap()) {
-                        folder
-                            .entries
-                            .insert(newname.to_str().unwrap().to_string(), inode);
-                        Ok(())
-                    } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::NotFound,
-                            format!("File or folder not found: {olddir} {oldname:?}"),
-                        ))
-                    }
-                }
-                _ => Err(io::Error::new(
-                    io::ErrorKind::NotADirectory,
-                    format!("Parent is not a direcoty: {olddir}"),
-                )),
-            }
+        if let Some(reusable) = self.reusable_inode_queue.write().unpoisoned().pop_back() {
+            Ok(reusable)
         } else {
-            let new_dir_node = self.load(newdir)?;
-            let new_dir_node = &mut new_dir_node.write().unwrap();
-            match (&mut old_dir_node.inner, &mut new_dir_node.inner) {
-                (InnerNode::Folder(old_folder), InnerNode::Folder(new_folder)) => {
-                    if let Some(inode) = old_folder.entries.remove(oldname.to_str().unwrap()) {
-                        new_folder
-                            .entries
-                            .insert(newname.to_str().unwrap().to_string(), inode);
-                        Ok(())
-                    } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::NotFound,
-                            format!("File or folder not found: {olddir} {oldname:?}"),
-                        ))
-                    }
-                }
-                _ => Err(io::Error::new(
-                    io::ErrorKind::NotADirectory,
-                    format!("Parent is not a direcoty: {olddir}"),
-                )),
-            }
+            let mut nodes = self.nodes.write().unpoisoned();
+            nodes.push(None);
+            Ok((nodes.len() as Inode, 0)) // This should return the last index + 1 (inode 0 is invalid). Now a None value
         }
     }
 
-    fn open(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        inode: Self::Inode,
-        flags: u32,
-        fuse_flags: u32,
-    ) -> io::Result<(
-        Option<Self::Handle>,
-        fuse_backend_rs::abi::fuse_abi::OpenOptions,
-        Option<u32>,
-    )> {
-        let _ = fuse_flags;
-        let _ = flags;
-        let _ = ctx;
-        self.load(inode)?;
-        Ok((None, OpenOptions::empty(), None))
+    /// Gives back an inode from `next_inode` whose node never became reachable, because the journal could not be written.
+    /// The node is removed from the table if it was stored there already.
+    fn release_inode(&self, inode: Inode, generation: u64) {
+        self.used_inodes.fetch_sub(1, Ordering::AcqRel);
+        let mut nodes = self.nodes.write().unpoisoned();
+        nodes[inode as usize - 1] = None;
+        // The kernel never saw the node, so the generation does not have to change
+        let mut queue = self.reusable_inode_queue.write().unpoisoned();
+        queue.push_back((inode, generation));
     }
 
-    fn read(
+    /// Hands out a new handle with the open `flags` for the locked `node`.
+    /// The handle keeps the node alive until it is released.
+    fn open_handle(&self, node: &mut Node, flags: u32) -> Handle {
+        node.open_handles += 1;
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let state = OpenHandle {
+            inode: node.inode,
+            append: flags & libc::O_APPEND as u32 != 0,
+        };
+        self.open_handles.write().unpoisoned().insert(handle, state);
+        handle
+    }
+
+    /// The state of `handle`, which has to be handed out for `inode` and not be released yet
+    fn handle_state(&self, inode: Inode, handle: Handle) -> Result<OpenHandle, Error> {
+        match self.open_handles.read().unpoisoned().get(&handle) {
+            Some(state) if state.inode == inode => Ok(*state),
+            _ => Err(Error::BadHandle(handle)),
+        }
+    }
+
+    /// Frees `handle`, which has to be handed out for `inode`.
+    /// The node is reclaimed if the handle was the last thing referring to it.
+    fn release_handle(&self, inode: Inode, handle: Handle) -> Result<(), Error> {
+        let mut open_handles = self.open_handles.write().unpoisoned();
+        if open_handles
+            .get(&handle)
+            .is_none_or(|state| state.inode != inode)
+        {
+            return Err(Error::BadHandle(handle));
+        }
+        open_handles.remove(&handle);
+        drop(open_handles);
+
+        let node = self.load(inode)?;
+        let mut node = node.write().unpoisoned();
+        node.open_handles = node.open_handles.saturating_sub(1);
+        self.discard_unreachable_content(&node);
+        if node.can_reclaim() {
+            self.reclaim_inode(&mut node);
+        }
+        Ok(())
+    }
+
+    /// Lists the entries of the folder `inode` after the cookie `offset`, starting with "." and "..".
+    /// `add_entry` gets the locked child of each real entry and returns 0 once the reply is full.
+    fn list_folder(
         &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        inode: Self::Inode,
-        handle: Self::Handle,
-        w: &mut dyn fuse_backend_rs::api::filesystem::ZeroCopyWriter,
-        size: u32,
+        inode: Inode,
+        handle: Handle,
         offset: u64,
-        lock_owner: Option<u64>,
-        flags: u32,
-    ) -> io::Result<usize> {
-        // unused
-        let _ = flags;
-        let _ = lock_owner;
-        let _ = handle;
-        let _ = ctx;
-        debug!("Read {inode} with size {size} and offset {offset}");
+        add_entry: &mut dyn FnMut(DirEntry, Option<&Node>) -> io::Result<usize>,
+    ) -> Result<(), Error> {
+        self.handle_state(inode, handle)?;
+
         let node = self.load(inode)?;
-        let node1 = &*node.read().unwrap();
-        match &node1.inner {
-            InnerNode::File(file) => {
-                let offset = offset as usize;
-                let size = size as usize;
-                let data = file.data.read().unwrap();
-                let mut range = offset..(offset + size);
-                // if range.start >= MAX_FILE_SIZE {
-                //     range.start = MAX_FILE_SIZE;
-                // }
-                // if range.end >= MAX_FILE_SIZE {
-                //     range.end = MAX_FILE_SIZE;
-                // }
-                if range.start >= data.len() {
-                    range.start = data.len();
-                }
-                if range.end >= data.len() {
-                    range.end = data.len();
+        let node1 = node.read().unpoisoned();
+        let folder = node1.folder()?;
+        let dots = [
+            (DOT_COOKIE, OsStr::new("."), inode),
+            (DOT_DOT_COOKIE, OsStr::new(".."), folder.parent),
+        ];
+        let entries = dots
+            .into_iter()
+            .filter(|&(cookie, _, _)| cookie > offset)
+            .chain(folder.list_after(offset));
+        let mut listed = false;
+        for (cookie, name, child_inode) in entries {
+            let mut entry = DirEntry {
+                ino: child_inode,
+                offset: cookie,
+                type_: libc::DT_DIR as u32,
+                name: name.as_bytes(),
+            };
+            let added = if cookie < FIRST_ENTRY_COOKIE {
+                add_entry(entry, None).map_err(Error::from)
+            } else {
+                self.load(child_inode).and_then(|child| {
+                    let child = child.read().unpoisoned();
+                    entry.type_ = child.dirent_type();
+                    Ok(add_entry(entry, Some(&child))?)
+                })
+            };
+            match added {
+                Ok(0) => break,
+                Ok(_) => listed = true,
+                // The entries that were added are still replied, so the kernel knows about every lookup they took.
+                // The next request continues after them and fails instead.
+                Err(e) if listed => {
+                    error!("Listing {inode} stopped early: {e:?}");
+                    break;
                 }
-                w.write_all(&data[range.clone()]).unwrap();
-                let written = range.count();
+                Err(e) => return Err(e),
+            }
+        }
 
-                debug!("Reading with size {written}");
+        if node1.atime_outdated() {
+            drop(node1);
+            node.write().unpoisoned().update_atime();
+        }
+        Ok(())
+    }
 
-                Ok(written)
-            }
-            _ => Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("File could not be found: {inode}"),
-            )),
+    /// Drops a miss the kernel may have cached for the name `name` in `parent`, which exists now.
+    /// Misses are only cached with a negative timeout, so there is nothing to drop without one.
+    fn invalidate_miss(&self, parent: Inode, name: &OsStr) {
+        if let Some(notifier) = &self.notifier
+            && !self.timeouts.negative.is_zero()
+        {
+            notifier.invalidate_entry(parent, name);
         }
     }
 
-    fn write(
-        &self,
-        ctx: &fuse_backend_rs::api::filesystem::Context,
-        inode: Self::Inode,
-        handle: Self::Handle,
-        r: &mut dyn fuse_backend_rs::api::filesystem::ZeroCopyReader,
-        size: u32,
-        offset: u64,
-        lock_owner: Option<u64>,
-        delayed_write: bool,
-        flags: u32,
-        fuse_flags: u32,
-    ) -> io::Result<usize> {
-        let _ = delayed_write;
-        let _ = lock_owner;
-        let _ = ctx;
-        debug!(
-            "Write inode {inode} handle {handle} size {size} offset {offset} flags {flags} fuse_flags {fuse_flags} "
-        );
-        let node = self.load(inode)?;
-        let node1 = &*node.read().unwrap();
-        match &node1.inner {
-            InnerNode::File(file) => {
-                let mut data = file.data.write().unwrap();
+    /// Empties the locked `node` if it is a file opened with O_TRUNC.
+    /// Other nodes ignore the flag like on other filesystems.
+    fn truncate_on_open(&self, node: &mut Node, flags: u32) -> Result<(), Error> {
+        if flags & libc::O_TRUNC as u32 == 0 || !matches!(node.inner, InnerNode::File(_)) {
+            return Ok(());
+        }
+        Self::check_resize(node, 0)?;
+        let attributes = node.attributes();
+        let mut transaction = self.transaction();
+        node.update_mtime();
+        transaction.truncate(node.inode, 0);
+        transaction.attr(node);
+        if let Err(e) = self.commit(transaction) {
+            node.restore_attributes(attributes);
+            return Err(e.into());
+        }
+        self.resize(node, 0);
+        Ok(())
+    }
 
-                let mut buf = Vec::with_capacity(BLOCK_SIZE);
-                let buf_size = r.read_to_end(&mut buf).unwrap();
+    /// Checks that the locked `node` is a file that can have the size `size` for setattr or O_TRUNC
+    fn check_resize(node: &Node, size: u64) -> Result<(), Error> {
+        match node.inner {
+            InnerNode::File(_) if size > MAX_FILE_SIZE => Err(Error::FileTooLarge),
+            InnerNode::File(_) => Ok(()),
+            InnerNode::Folder(_) => Err(Error::IsADirectory(node.inode)),
+            _ => Err(Error::InvalidArgument),
+        }
+    }
 
-                if buf_size != size as usize {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        format!(
-                            "The buffer size({buf_size}) and size paramter({size}) are not the same"
-                        ),
-                    ));
-                }
+    /// Changes the size of the locked file `node` once the change is committed.
+    /// Growing it only adds a hole, which takes up no space.
+    fn resize(&self, node: &Node, size: u64) {
+        if let InnerNode::File(file) = &node.inner {
+            let mut data = file.data.write().unpoisoned();
+            let allocated = data.allocated();
+            data.truncate(size);
+            self.release_bytes(allocated - data.allocated());
+        }
+    }
 
-                let mut range = offset as usize..(offset as usize + buf_size);
+    /// Takes `bytes` more bytes of file content out of the byte budget or returns ENOSPC
+    fn reserve_bytes(&self, bytes: u64) -> Result<(), Error> {
+        Self::reserve(&self.used_bytes, bytes, self.max_bytes)
+    }
 
-                if range.start >= data.len() {
-                    range.start = data.len();
-                }
-                if range.end >= data.len() {
-                    range.end = data.len();
-                }
+    /// Gives `bytes` bytes of file content back to the byte budget
+    fn release_bytes(&self, bytes: u64) {
+        self.used_bytes.fetch_sub(bytes, Ordering::AcqRel);
+    }
 
-                if range.start >= MAX_FILE_SIZE {
-                    range.start = MAX_FILE_SIZE;
-                }
-                if range.end >= MAX_FILE_SIZE {
-                    range.end = MAX_FILE_SIZE;
-                }
+    fn reserve(used: &AtomicU64, amount: u64, max: Option<u64>) -> Result<(), Error> {
+        used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(amount)
+                .filter(|&used| max.is_none_or(|max| used <= max))
+        })
+        .map(|_| ())
+        .map_err(|_| Error::NoSpace)
+    }
+}
 
-                data.splice(range, buf);
+const MAX_FILE_SIZE: u64 = 4294967296; // 4GiB / 4.29 GB
+const BLOCK_SIZE: usize = 4096;
+/// The permission bits including the set-user-ID, set-group-ID and sticky bit
+const PERMISSION_BITS: u32 = 0o7777;
+/// The access time is updated at least this often, even if the content did not change
+const ATIME_UPDATE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// The namespaces an extended attribute name has to start with
+const XATTR_NAMESPACES: [&[u8]; 3] = [b"user.", b"trusted.", b"security."];
+/// The longest allowed name of a directory entry (NAME_MAX)
+const MAX_NAME_SIZE: usize = 255;
+/// The longest allowed extended attribute name (XATTR_NAME_MAX)
+const MAX_XATTR_NAME_SIZE: usize = 255;
+/// The largest allowed extended attribute value (XATTR_SIZE_MAX)
+const MAX_XATTR_VALUE_SIZE: usize = 65536;
+/// The space all names and values of the extended attributes of one node can take up
+const MAX_XATTR_TOTAL_SIZE: usize = 65536;
 
-                debug!("Writing to file {buf_size}");
-                Ok(buf_size)
-            }
-            _ => Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("File could not be found {inode}"),
-            )),
+/// The file types mknod can create besides regular files
+const SPECIAL_FILE_TYPES: [u32; 4] = [libc::S_IFCHR, libc::S_IFBLK, libc::S_IFIFO, libc::S_IFSOCK];
+
+type Inode = u64;
+type Handle = u64;
+
+/// The inode of the root folder of the filesystem
+const ROOT_INODE: Inode = 1;
+
+/// The cookies readdir hands out for the "." and ".." entries. The ones of the other entries follow them.
+/// A listing starts at cookie 0 and continues after the cookie of the last entry it returned.
+const DOT_COOKIE: u64 = 1;
+const DOT_DOT_COOKIE: u64 = 2;
+const FIRST_ENTRY_COOKIE: u64 = 3;
+
+impl FileSystem for MyFileSystem<'_> {
+    type Inode = Inode;
+    type Handle = Handle;
+
+    fn init(&self, capable: FsOptions) -> std::io::Result<FsOptions> {
+        let _ = capable; // unused
+        let mut nodes = self.nodes.write().unpoisoned();
+        // A filesystem loaded from an image already has its root
+        if nodes.is_empty() {
+            // The root belongs to the user running the filesystem
+            // SAFETY: getuid and getgid have no preconditions and can not fail
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            let root_node = Node::new_folder(ROOT_INODE, ROOT_INODE, uid, gid, 0o755);
+            let mut transaction = self.transaction();
+            transaction.create(&root_node);
+            self.commit(transaction)?;
+            self.used_inodes.fetch_add(1, Ordering::AcqRel);
+            nodes.push(Some(Arc::new(RwLock::new(root_node))));
         }
+        info!("Filesystem Init");
+        Ok(FsOptions::ASYNC_READ
+            | FsOptions::BIG_WRITES
+            | FsOptions::ASYNC_DIO
+            | FsOptions::PARALLEL_DIROPS
+            // Open and create truncate files themselves instead of getting a separate setattr
+            | FsOptions::ATOMIC_O_TRUNC
+            // Listings carry the attributes of each entry, so `ls -l` needs no lookup per entry
+            | FsOptions::DO_READDIRPLUS
+            | FsOptions::READDIRPLUS_AUTO
+            | FsOptions::ZERO_MESSAGE_OPEN
+            | FsOptions::ZERO_MESSAGE_OPENDIR)
     }
 
-    fn flush(
+    fn lookup(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        let _ = ctx;
+        debug!("Lookup parent={parent} name={name:?}");
+
+        let parent = self.load(parent)?;
+        // The parent stays locked, so the entry can not be removed before it is counted
+        let parent = parent.read().unpoisoned();
+        let inode = match parent.entry(entry_name(name)?) {
+            Ok(inode) => inode,
+            // An entry without inode lets the kernel cache the miss for the negative timeout
+            Err(Error::EntryNotFound(..)) if !self.timeouts.negative.is_zero() => {
+                return Ok(Entry {
+                    entry_timeout: self.timeouts.negative,
+                    ..Entry::default()
+                });
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let node = self.load(inode)?;
+        let entry = node.read().unpoisoned().lookup_entry(&self.timeouts);
+        Ok(entry)
+    }
+
+    fn forget(
         &self,
         ctx: &fuse_backend_rs::api::filesystem::Context,
         inode: Self::Inode,
-        handle: Self::Handle,
-        lock_owner: u64,
-    ) -> io::Result<()> {
-        let _ = lock_owner;
-        let _ = handle;
+        count: u64,
+    ) {
         let _ = ctx;
-        debug!("Flush {inode}");
-        Ok(())
+        debug!("Forget {inode} count {count}");
+        // There is no reply to forget, so an unknown inode is just ignored
+        let Ok(node) = self.load(inode) else {
+            return;
+        };
+        let mut node = node.write().unpoisoned();
+        let lookups = node.lookups.get_mut();
+        *lookups = lookups.saturating_sub(count);
+        if node.can_reclaim() {
+            self.reclaim_inode(&mut node);
+        }
     }
 
-    fn release(
+    fn getattr(
         &self,
         ctx: &fuse_backend_rs::api::filesystem::Context,
         inode: Self::Inode,
-        flags: u32,
-        handle: Self::Handle,
-        flush: bool,
-        flock_release: bool,
-        lock_owner: Option<u64>,
-    ) -> io::Result<()> {
-        let _ = lock_owner;
-        let _ = flock_release;
-        let _ = flush;
-        let _ = handle;
-        let _ = flags;
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(stat64, Duration)> {
         let _ = ctx;
-        debug!("Release {inode}");
-        Ok(())
+        debug!("Getting attributes for inode {inode} and handle {handle:?}");
+
+        if let Some(handle) = handle {
+            self.handle_state(inode, handle)?;
+        }
+        let node = self.load(inode)?;
+        let entry = node.read().unpoisoned().get_entry(&self.timeouts);
+        Ok((entry.attr, self.timeouts.attr))
     }
 
-    fn releasedir(
+    fn setattr(
         &self,
         ctx: &fuse_backend_rs::api::filesystem::Context,
         inode: Self::Inode,
-        flags: u32,
-        handle: Self::Handle,
-    ) -> io::Result<()> {
-        Ok(())
+        attr: stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        let _ = ctx;
+        debug!("setattr {valid:?} {attr:#?}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        if let Some(handle) = handle {
+            self.handle_state(inode, handle)?;
+        }
+        // Permission checks are done by the kernel because of the default_permissions mount option
+        // Times are converted up front, so nothing is changed if one of them is out of range
+        let time = |valid_bit, secs, nanos| {
+            valid
+                .contains(valid_bit)
+                .then(|| from_unix_time(secs, nanos).ok_or(Error::InvalidArgument))
+                .transpose()
+        };
+        let atime = time(SetattrValid::ATIME, attr.st_atime, attr.st_atime_nsec)?;
+        let mtime = time(SetattrValid::MTIME, attr.st_mtime, attr.st_mtime_nsec)?;
+        let ctime = time(SetattrValid::CTIME, attr.st_ctime, attr.st_ctime_nsec)?;
+        let node = self.load(inode)?;
+        let mut node = node.write().unpoisoned();
+        let attributes = node.attributes();
+        // The size is checked first, so nothing is changed if it is invalid
+        let size = valid
+            .contains(SetattrValid::SIZE)
+            .then_some(attr.st_size as u64);
+        let mut transaction = self.transaction();
+        if let Some(size) = size {
+            Self::check_resize(&node, size)?;
+            node.update_mtime();
+            transaction.truncate(inode, size);
+        }
+        if valid.contains(SetattrValid::MODE) {
+            node.mode = attr.st_mode & PERMISSION_BITS;
+        }
+        if valid.contains(SetattrValid::UID) {
+            node.uid = attr.st_uid;
+        }
+        if valid.contains(SetattrValid::GID) {
+            node.gid = attr.st_gid;
+        }
+        if valid.contains(SetattrValid::ATIME_NOW) {
+            node.atime = SystemTime::now();
+        } else if let Some(atime) = atime {
+            node.atime = atime;
+        }
+        if valid.contains(SetattrValid::MTIME_NOW) {
+            node.mtime = SystemTime::now();
+        } else if let Some(mtime) = mtime {
+            node.mtime = mtime;
+        }
+        // Every attribute change is a metadata change
+        if let Some(ctime) = ctime {
+            node.ctime = ctime;
+        } else {
+            node.update_ctime();
+        }
+        transaction.attr(&node);
+        if let Err(e) = self.commit(transaction) {
+            node.restore_attributes(attributes);
+            return Err(e);
+        }
+        if let Some(size) = size {
+            self.resize(&node, size);
+        }
+        let entry = node.get_entry(&self.timeouts);
+        Ok((entry.attr, self.timeouts.attr))
     }
-}
 
-/// This struct is just used for logging all requests
-struct LoggingMetricsHook {}
+    /////////////////////////////
+    // Directory Operations
+    /////////////////////////////
+
+    fn mkdir(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        debug!("mkdir {parent} {name:?} mode={mode:o} umask={umask:o}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let name = entry_name(name)?;
+        let parent = self.load(parent)?;
+        let mut parent = parent.write().unpoisoned();
+        let (uid, gid) = parent.child_owner(ctx);
+        // Subfolders inherit the set-group-ID bit so the group keeps being inherited
+        let mode = (mode & !umask) | (parent.mode & libc::S_ISGID);
+        let parent_inode = parent.inode;
+        let attributes = parent.attributes();
+        match &mut parent.inner {
+            InnerNode::File(_) | InnerNode::Symlink(_) | InnerNode::Special(_) => {
+                Err(Error::NotADirectory(parent.inode).into())
+            }
+            InnerNode::Folder(folder) => {
+                if folder.contains(name) {
+                    return Err(Error::AlreadyExists(parent_inode, name.to_owned()).into());
+                }
+                let (inode, generation) = self.next_inode()?;
+                let mut new_folder = Node::new_folder(inode, parent_inode, uid, gid, mode);
+                new_folder.generation = generation;
+                debug!("created node {new_folder:#?}");
+                let entry = new_folder.lookup_entry(&self.timeouts);
+                let mut transaction = self.transaction();
+                transaction.create(&new_folder);
+                let mut nodes = self.nodes.write().unpoisoned();
+                nodes[inode as usize - 1] = Some(Arc::new(RwLock::new(new_folder)));
+                drop(nodes);
+                folder.insert(name, inode);
+                // The ".." entry of the new folder points to the parent
+                parent.nlink += 1;
+                parent.update_mtime();
+                transaction.link(parent.inode, name, inode);
+                transaction.attr(&parent);
+                if let Err(e) = self.commit(transaction) {
+                    parent.folder_mut()?.remove(name);
+                    parent.restore_attributes(attributes);
+                    self.release_inode(inode, generation);
+                    return Err(e);
+                }
+                self.invalidate_miss(parent.inode, name);
+
+                Ok(entry)
+            }
+        }
+    }
+
+    fn rmdir(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<()> {
+        let _ = ctx;
+        debug!("rmdir parent={parent} name={name:?}");
+        Ok(self.remove_entry(parent, name, true)?)
+    }
+
+    fn opendir(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        let _ = ctx;
+        let node = self.load(inode)?;
+        let mut node = node.write().unpoisoned();
+        node.folder()?;
+        let handle = self.open_handle(&mut node, flags);
+        debug!("Opendir {inode} with handle {handle}");
+        Ok((Some(handle), OpenOptions::empty()))
+    }
+
+    fn readdir(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(fuse_backend_rs::api::filesystem::DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        // The buffer of the reply is limited by add_entry, which returns 0 once an entry does not fit anymore
+        let _ = size;
+        let _ = ctx;
+        debug!("Reading directory {} with offset {offset}", inode);
+        self.list_folder(inode, handle, offset, &mut |entry, _| add_entry(entry))?;
+        Ok(())
+    }
+
+    fn readdirplus(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(
+            fuse_backend_rs::api::filesystem::DirEntry,
+            Entry,
+        ) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let _ = size;
+        let _ = ctx;
+        debug!(
+            "Reading directory {} plus attributes with offset {offset}",
+            inode
+        );
+        self.list_folder(inode, handle, offset, &mut |dir_entry, child| {
+            // The kernel ignores the entries of "." and "..", an inode of 0 tells it there is none
+            let Some(child) = child else {
+                return add_entry(dir_entry, Entry::default());
+            };
+            // Like a lookup the kernel keeps a reference to every entry, but only if it made it into the reply.
+            // The child stays locked, so it can not be forgotten in between.
+            let added = add_entry(dir_entry, child.get_entry(&self.timeouts))?;
+            if added > 0 {
+                child.lookups.fetch_add(1, Ordering::AcqRel);
+            }
+            Ok(added)
+        })?;
+        Ok(())
+    }
+
+    /////////////////////////
+    // File Operations
+    /////////////////////////
+
+    fn mknod(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        debug!("mknod {inode} {name:?} mode={mode:o} rdev={rdev} umask={umask:o}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let file_type = mode & libc::S_IFMT;
+        if file_type != 0 && file_type != libc::S_IFREG && !SPECIAL_FILE_TYPES.contains(&file_type)
+        {
+            return Err(Error::InvalidArgument.into());
+        }
+        let name = entry_name(name)?;
+        let parent = self.load(inode)?;
+        let mut parent = parent.write().unpoisoned();
+        let (uid, gid) = parent.child_owner(ctx);
+        let attributes = parent.attributes();
+        match &mut parent.inner {
+            InnerNode::File(_) | InnerNode::Symlink(_) | InnerNode::Special(_) => {
+                Err(Error::NotADirectory(parent.inode).into())
+            }
+            InnerNode::Folder(folder) => {
+                if folder.contains(name) {
+                    return Err(Error::AlreadyExists(inode, name.to_owned()).into());
+                }
+                let (new_inode, generation) = self.next_inode()?;
+                let mut new_file = if SPECIAL_FILE_TYPES.contains(&file_type) {
+                    Node::new_special(new_inode, uid, gid, mode & !umask, file_type, rdev)
+                } else {
+                    Node::new_file(new_inode, uid, gid, mode & !umask)
+                };
+                new_file.generation = generation;
+                debug!("created node {new_file:#?}");
+                let entry = new_file.lookup_entry(&self.timeouts);
+                let mut transaction = self.transaction();
+                transaction.create(&new_file);
+                // The node is in the table before its entry is added, so the entry always resolves
+                let mut nodes = self.nodes.write().unpoisoned();
+                nodes[new_inode as usize - 1] = Some(Arc::new(RwLock::new(new_file)));
+                drop(nodes);
+                folder.insert(name, new_inode);
+                parent.update_mtime();
+                transaction.link(parent.inode, name, new_inode);
+                transaction.attr(&parent);
+                if let Err(e) = self.commit(transaction) {
+                    parent.folder_mut()?.remove(name);
+                    parent.restore_attributes(attributes);
+                    self.release_inode(new_inode, generation);
+                    return Err(e);
+                }
+                self.invalidate_miss(parent.inode, name);
+
+                Ok(entry)
+            }
+        }
+    }
+
+    fn link(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        let _ = ctx;
+        debug!("link inode={inode} newparent={newparent} newname={newname:?}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let name = entry_name(newname)?;
+        let node = self.load(inode)?;
+        // link(2) reports EPERM for hard links to folders.
+        // Everything else has no children, so it can be locked after the new parent.
+        if let InnerNode::Folder(_) = node.read().unpoisoned().inner {
+            return Err(Error::NotPermitted(inode).into());
+        }
+        let parent = self.load(newparent)?;
+        let mut parent = parent.write().unpoisoned();
+        let parent_attributes = parent.attributes();
+        match &mut parent.inner {
+            InnerNode::File(_) | InnerNode::Symlink(_) | InnerNode::Special(_) => {
+                Err(Error::NotADirectory(parent.inode).into())
+            }
+            InnerNode::Folder(folder) => {
+                if folder.contains(name) {
+                    return Err(Error::AlreadyExists(newparent, name.to_owned()).into());
+                }
+                let mut node = node.write().unpoisoned();
+                // Like link(2), a node that was removed everywhere can not get a name again
+                if node.nlink == 0 {
+                    return Err(Error::InodeNotFound(inode).into());
+                }
+                let node_attributes = node.attributes();
+                folder.insert(name, inode);
+                parent.update_mtime();
+                node.nlink += 1;
+                node.update_ctime();
+                let mut transaction = self.transaction();
+                transaction.link(parent.inode, name, inode);
+                transaction.attr(&parent);
+                transaction.attr(&node);
+                if let Err(e) = self.commit(transaction) {
+                    parent.folder_mut()?.remove(name);
+                    parent.restore_attributes(parent_attributes);
+                    node.restore_attributes(node_attributes);
+                    return Err(e);
+                }
+                self.invalidate_miss(newparent, name);
+
+                Ok(node.lookup_entry(&self.timeouts))
+            }
+        }
+    }
+
+    fn symlink(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        debug!("symlink parent={parent} name={name:?} linkname={linkname:?}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let name = entry_name(name)?;
+        let parent = self.load(parent)?;
+        let mut parent = parent.write().unpoisoned();
+        let (uid, gid) = parent.child_owner(ctx);
+        let parent_inode = parent.inode;
+        let attributes = parent.attributes();
+        match &mut parent.inner {
+            InnerNode::File(_) | InnerNode::Symlink(_) | InnerNode::Special(_) => {
+                Err(Error::NotADirectory(parent.inode).into())
+            }
+            InnerNode::Folder(folder) => {
+                if folder.contains(name) {
+                    return Err(Error::AlreadyExists(parent_inode, name.to_owned()).into());
+                }
+                let (new_inode, generation) = self.next_inode()?;
+                let mut new_symlink =
+                    Node::new_symlink(new_inode, uid, gid, linkname.to_bytes().to_vec());
+                new_symlink.generation = generation;
+                debug!("created symlink {new_symlink:#?}");
+                let entry = new_symlink.lookup_entry(&self.timeouts);
+                let mut transaction = self.transaction();
+                transaction.create(&new_symlink);
+                // The node is in the table before its entry is added, so the entry always resolves
+                let mut nodes = self.nodes.write().unpoisoned();
+                nodes[new_inode as usize - 1] = Some(Arc::new(RwLock::new(new_symlink)));
+                drop(nodes);
+                folder.insert(name, new_inode);
+                parent.update_mtime();
+                transaction.link(parent.inode, name, new_inode);
+                transaction.attr(&parent);
+                if let Err(e) = self.commit(transaction) {
+                    parent.folder_mut()?.remove(name);
+                    parent.restore_attributes(attributes);
+                    self.release_inode(new_inode, generation);
+                    return Err(e);
+                }
+                self.invalidate_miss(parent_inode, name);
+
+                Ok(entry)
+            }
+        }
+    }
+
+    fn readlink(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+    ) -> io::Result<Vec<u8>> {
+        let _ = ctx;
+        debug!("readlink {inode}");
+        let node = self.load(inode)?;
+        let node = node.read().unpoisoned();
+        match &node.inner {
+            InnerNode::Symlink(symlink) => Ok(symlink.target.clone()),
+            // readlink(2) reports EINVAL for anything that is not a symbolic link
+            _ => Err(Error::InvalidArgument.into()),
+        }
+    }
+
+    fn unlink(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<()> {
+        let _ = ctx;
+        debug!("unlink parent={parent} name={name:?}");
+        Ok(self.remove_entry(parent, name, false)?)
+    }
+
+    fn rename(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        debug!("rename {olddir} {oldname:?} to {newdir} {newname:?} flags={flags:#x}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let known_flags = libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE | libc::RENAME_WHITEOUT;
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        if flags & !known_flags != 0 || (exchange && flags != libc::RENAME_EXCHANGE) {
+            return Err(Error::InvalidArgument.into());
+        }
+        let (oldname, newname) = (entry_name(oldname)?, entry_name(newname)?);
+
+        if olddir == newdir {
+            let dir = self.load(olddir)?;
+            let mut dir = dir.write().unpoisoned();
+            return Ok(self.rename_locked(ctx, &mut dir, None, oldname, newname, flags)?);
+        }
+
+        let _rename = self.rename_lock.lock().unpoisoned();
+        let old_dir = self.load(olddir)?;
+        let new_dir = self.load(newdir)?;
+        loop {
+            // The entries are looked up without holding the folders, because checking
+            // the ancestors has to lock them. They are checked again once the folders are locked.
+            let moved = old_dir.read().unpoisoned().entry(oldname)?;
+            let target = new_dir.read().unpoisoned().folder()?.get(newname);
+            // A folder can not be moved below itself
+            if self.is_ancestor(moved, newdir)? {
+                return Err(Error::InvalidArgument.into());
+            }
+            // A folder that contains the moved node is not empty and can not be replaced
+            if let Some(target) = target
+                && target != moved
+                && self.is_ancestor(target, olddir)?
+            {
+                let error = if flags & libc::RENAME_NOREPLACE != 0 {
+                    Error::AlreadyExists(newdir, newname.to_owned())
+                } else if exchange {
+                    Error::InvalidArgument
+                } else {
+                    Error::NotEmpty(target)
+                };
+                return Err(error.into());
+            }
+
+            // Folders are locked from the top down like everywhere else and unrelated ones in inode order
+            let old_first = !self.is_ancestor(newdir, olddir)?
+                && (self.is_ancestor(olddir, newdir)? || olddir < newdir);
+            let (mut old_dir, mut new_dir) = if old_first {
+                let old_dir = old_dir.write().unpoisoned();
+                (old_dir, new_dir.write().unpoisoned())
+            } else {
+                let new_dir = new_dir.write().unpoisoned();
+                (old_dir.write().unpoisoned(), new_dir)
+            };
+            // Another request may have changed the entries in the meantime, then it starts over
+            if old_dir.folder()?.get(oldname) == Some(moved)
+                && new_dir.folder()?.get(newname) == target
+            {
+                return Ok(self.rename_locked(
+                    ctx,
+                    &mut old_dir,
+                    Some(&mut new_dir),
+                    oldname,
+                    newname,
+                    flags,
+                )?);
+            }
+        }
+    }
+
+    fn open(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(
+        Option<Self::Handle>,
+        fuse_backend_rs::abi::fuse_abi::OpenOptions,
+        Option<u32>,
+    )> {
+        let _ = fuse_flags;
+        let _ = ctx;
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let node = self.load(inode)?;
+        let mut node = node.write().unpoisoned();
+        self.truncate_on_open(&mut node, flags)?;
+        let handle = self.open_handle(&mut node, flags);
+        debug!("Open {inode} with flags {flags:#o} and handle {handle}");
+        Ok((Some(handle), OpenOptions::empty(), None))
+    }
+
+    fn create(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions, Option<u32>)> {
+        debug!(
+            "create {parent} {name:?} flags={:#o} mode={:o} umask={:o}",
+            args.flags, args.mode, args.umask
+        );
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let name = entry_name(name)?;
+        let parent = self.load(parent)?;
+        // The parent stays locked from the check until the new entry is added, so only one creator wins
+        let mut parent = parent.write().unpoisoned();
+        let parent_inode = parent.inode;
+
+        // Without O_EXCL an existing file is opened instead
+        if let Some(inode) = parent.folder()?.get(name) {
+            if args.flags & libc::O_EXCL as u32 != 0 {
+                return Err(Error::AlreadyExists(parent_inode, name.to_owned()).into());
+            }
+            let node = self.load(inode)?;
+            let mut node = node.write().unpoisoned();
+            if let InnerNode::Folder(_) = node.inner {
+                return Err(Error::IsADirectory(inode).into());
+            }
+            self.truncate_on_open(&mut node, args.flags)?;
+            let handle = self.open_handle(&mut node, args.flags);
+            debug!("Create opened existing {inode} with handle {handle}");
+            return Ok((
+                node.lookup_entry(&self.timeouts),
+                Some(handle),
+                OpenOptions::empty(),
+                None,
+            ));
+        }
+
+        let (uid, gid) = parent.child_owner(ctx);
+        let (inode, generation) = self.next_inode()?;
+        let mut new_file = Node::new_file(inode, uid, gid, args.mode & !args.umask);
+        new_file.generation = generation;
+        debug!("created node {new_file:#?}");
+        let entry = new_file.lookup_entry(&self.timeouts);
+        let attributes = parent.attributes();
+        let mut transaction = self.transaction();
+        transaction.create(&new_file);
+        // The node is in the table before its entry is added, so the entry always resolves
+        let new_file = Arc::new(RwLock::new(new_file));
+        self.nodes.write().unpoisoned()[inode as usize - 1] = Some(new_file.clone());
+        parent.folder_mut()?.insert(name, inode);
+        parent.update_mtime();
+        transaction.link(parent_inode, name, inode);
+        transaction.attr(&parent);
+        if let Err(e) = self.commit(transaction) {
+            parent.folder_mut()?.remove(name);
+            parent.restore_attributes(attributes);
+            self.release_inode(inode, generation);
+            return Err(e);
+        }
+        self.invalidate_miss(parent_inode, name);
+
+        let handle = self.open_handle(&mut new_file.write().unpoisoned(), args.flags);
+        Ok((entry, Some(handle), OpenOptions::empty(), None))
+    }
+
+    fn read(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: &mut dyn fuse_backend_rs::api::filesystem::ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        // unused
+        let _ = flags;
+        let _ = lock_owner;
+        let _ = ctx;
+        debug!("Read {inode} with size {size} and offset {offset}");
+        self.handle_state(inode, handle)?;
+        let node = self.load(inode)?;
+        let node1 = node.read().unpoisoned();
+        let result = match &node1.inner {
+            InnerNode::File(file) => {
+                let data = file.data.read().unpoisoned();
+                let written = data.read(offset, size as usize, w)?;
+
+                debug!("Reading with size {written}");
+
+                Ok(written)
+            }
+            InnerNode::Folder(_) => Err(Error::IsADirectory(inode).into()),
+            _ => Err(Error::InvalidArgument.into()),
+        };
+
+        // Most reads do not update the access time, so the write lock is only taken when needed
+        if result.is_ok() && node1.atime_outdated() {
+            drop(node1);
+            node.write().unpoisoned().update_atime();
+        }
+        result
+    }
+
+    fn write(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: &mut dyn fuse_backend_rs::api::filesystem::ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        let _ = delayed_write;
+        let _ = lock_owner;
+        let _ = ctx;
+        debug!(
+            "Write inode {inode} handle {handle} size {size} offset {offset} flags {flags} fuse_flags {fuse_flags} "
+        );
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let append = self.handle_state(inode, handle)?.append;
+        let node = self.load(inode)?;
+        let node1 = &mut *node.write().unpoisoned();
+        match &node1.inner {
+            InnerNode::File(file) => {
+                let data = file.data.clone();
+                let mut data = data.write().unpoisoned();
+                // The end of the file can not move while the data is locked, so concurrent appends never overlap
+                let offset = if append { data.len() } else { offset };
+
+                let mut buf = Vec::with_capacity(BLOCK_SIZE);
+                let buf_size = r.read_to_end(&mut buf)?;
+
+                // The kernel always sends as many bytes as the size says
+                if buf_size != size as usize {
+                    return Err(Error::InvalidArgument.into());
+                }
+
+                if offset.saturating_add(buf_size as u64) > MAX_FILE_SIZE {
+                    return Err(Error::FileTooLarge.into());
+                }
+
+                // Only the chunks that are not allocated yet take up new space
+                let allocated = data.allocation_needed(offset, buf_size as u64);
+                self.reserve_bytes(allocated)?;
+                let attributes = node1.attributes();
+                node1.update_mtime();
+                let mut transaction = self.transaction();
+                transaction.write(inode, offset, &buf);
+                transaction.attr(node1);
+                if let Err(e) = self.commit(transaction) {
+                    self.release_bytes(allocated);
+                    node1.restore_attributes(attributes);
+                    return Err(e);
+                }
+                data.write(offset, &buf);
+
+                debug!("Writing to file {buf_size}");
+                Ok(buf_size)
+            }
+            InnerNode::Folder(_) => Err(Error::IsADirectory(inode).into()),
+            _ => Err(Error::InvalidArgument.into()),
+        }
+    }
+
+    fn statfs(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+    ) -> io::Result<statvfs64> {
+        let _ = ctx;
+        debug!("statfs {inode}");
+        let used_bytes = self.used_bytes.load(Ordering::Acquire);
+        let used_inodes = self.used_inodes.load(Ordering::Acquire);
+        // Without a limit the filesystem can grow as long as there is memory
+        let max_bytes = self.max_bytes.unwrap_or_else(total_memory).max(used_bytes);
+        let max_inodes = self.max_inodes.unwrap_or(u32::MAX as u64).max(used_inodes);
+
+        // SAFETY: statvfs64 only consists of integers, for which zero is a valid value
+        let mut st: statvfs64 = unsafe { std::mem::zeroed() };
+        st.f_bsize = BLOCK_SIZE as u64;
+        st.f_frsize = BLOCK_SIZE as u64;
+        st.f_blocks = max_bytes / BLOCK_SIZE as u64;
+        st.f_bfree = (max_bytes - used_bytes) / BLOCK_SIZE as u64;
+        st.f_bavail = st.f_bfree;
+        st.f_files = max_inodes;
+        st.f_ffree = max_inodes - used_inodes;
+        st.f_favail = st.f_ffree;
+        st.f_namemax = MAX_NAME_SIZE as u64;
+        Ok(st)
+    }
+
+    fn flush(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        let _ = lock_owner;
+        let _ = ctx;
+        debug!("Flush {inode} with handle {handle}");
+        self.handle_state(inode, handle)?;
+        Ok(())
+    }
+
+    fn release(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        let _ = lock_owner;
+        let _ = flock_release;
+        let _ = flush;
+        let _ = flags;
+        let _ = ctx;
+        debug!("Release {inode} with handle {handle}");
+        Ok(self.release_handle(inode, handle)?)
+    }
+
+    fn releasedir(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        let _ = flags;
+        let _ = ctx;
+        debug!("Releasedir {inode} with handle {handle}");
+        Ok(self.release_handle(inode, handle)?)
+    }
+
+    /////////////////////////
+    // Sparse Files
+    /////////////////////////
+
+    fn fallocate(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let _ = ctx;
+        debug!("Fallocate {inode} mode {mode} offset {offset} length {length}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        self.handle_state(inode, handle)?;
+        let mode = mode as i32;
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+        // Like fallocate(2), an empty range is invalid
+        if length == 0 {
+            return Err(Error::InvalidArgument.into());
+        }
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| end <= MAX_FILE_SIZE)
+            .ok_or(Error::FileTooLarge)?;
+
+        let node = self.load(inode)?;
+        let mut node = node.write().unpoisoned();
+        let InnerNode::File(file) = &node.inner else {
+            return Err(Error::NotAFile(inode).into());
+        };
+        let data = file.data.clone();
+        let mut data = data.write().unpoisoned();
+        let mode = mode & !libc::FALLOC_FL_KEEP_SIZE;
+        let mut transaction = self.transaction();
+        // Only allocating takes up new space, which is reserved before the journal is written
+        let reserved = match mode {
+            0 => {
+                transaction.allocate(inode, offset, length);
+                data.allocation_needed(offset, length)
+            }
+            // Punching a hole must never change the size of the file
+            libc::FALLOC_FL_PUNCH_HOLE if keep_size => {
+                transaction.punch_hole(inode, offset, length);
+                0
+            }
+            libc::FALLOC_FL_ZERO_RANGE => {
+                transaction.punch_hole(inode, offset, length);
+                transaction.allocate(inode, offset, length);
+                data.allocation_needed(offset, length)
+            }
+            _ => return Err(Error::NotSupported.into()),
+        };
+        self.reserve_bytes(reserved)?;
+        let grows = !keep_size && end > data.len();
+        if grows {
+            transaction.truncate(inode, end);
+        }
+        let attributes = node.attributes();
+        node.update_mtime();
+        transaction.attr(&node);
+        if let Err(e) = self.commit(transaction) {
+            self.release_bytes(reserved);
+            node.restore_attributes(attributes);
+            return Err(e);
+        }
+
+        match mode {
+            0 => data.allocate(offset, length),
+            libc::FALLOC_FL_ZERO_RANGE => data.zero_range(offset, length),
+            _ => {
+                let allocated = data.allocated();
+                data.punch_hole(offset, length);
+                self.release_bytes(allocated - data.allocated());
+            }
+        }
+        if grows {
+            data.truncate(end);
+        }
+        Ok(())
+    }
+
+    fn lseek(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let _ = ctx;
+        debug!("Lseek {inode} offset {offset} whence {whence}");
+        self.handle_state(inode, handle)?;
+        let node = self.load(inode)?;
+        let node = node.read().unpoisoned();
+        let InnerNode::File(file) = &node.inner else {
+            return Err(Error::InvalidArgument.into());
+        };
+        let data = file.data.read().unpoisoned();
+        // The kernel only asks for SEEK_DATA and SEEK_HOLE, everything else it resolves itself
+        let position = match whence as i32 {
+            libc::SEEK_DATA => data.seek_data(offset),
+            libc::SEEK_HOLE => data.seek_hole(offset),
+            _ => return Err(Error::InvalidArgument.into()),
+        };
+        Ok(position.ok_or(Error::NoSuchOffset(offset))?)
+    }
+
+    /////////////////////////
+    // Extended Attributes
+    /////////////////////////
+
+    fn setxattr(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        let _ = ctx;
+        debug!(
+            "setxattr {inode} {name:?} size={} flags={flags}",
+            value.len()
+        );
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        // The kernel already checks who may access which namespace
+        let name = name.to_bytes();
+        if !XATTR_NAMESPACES.iter().any(|ns| name.starts_with(ns)) {
+            return Err(Error::NotSupported.into());
+        }
+        if name.len() > MAX_XATTR_NAME_SIZE {
+            return Err(Error::OutOfRange.into());
+        }
+        if value.len() > MAX_XATTR_VALUE_SIZE {
+            return Err(Error::TooBig.into());
+        }
+
+        let node = self.load(inode)?;
+        let mut node = node.write().unpoisoned();
+        let existing = node.xattrs.get(name);
+        if flags & libc::XATTR_CREATE as u32 != 0 && existing.is_some() {
+            return Err(Error::AlreadyExists(inode, OsStr::from_bytes(name).to_owned()).into());
+        }
+        if flags & libc::XATTR_REPLACE as u32 != 0 && existing.is_none() {
+            return Err(Error::NoAttribute(inode).into());
+        }
+
+        let total_size: usize = node
+            .xattrs
+            .iter()
+            .filter(|(n, _)| n.as_slice() != name)
+            .map(|(n, v)| n.len() + v.len())
+            .sum();
+        if total_size + name.len() + value.len() > MAX_XATTR_TOTAL_SIZE {
+            return Err(Error::NoSpace.into());
+        }
+
+        let attributes = node.attributes();
+        node.update_ctime();
+        let mut transaction = self.transaction();
+        transaction.set_xattr(inode, name, value);
+        transaction.attr(&node);
+        if let Err(e) = self.commit(transaction) {
+            node.restore_attributes(attributes);
+            return Err(e);
+        }
+        node.xattrs.insert(name.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        let _ = ctx;
+        debug!("getxattr {inode} {name:?} size={size}");
+        let node = self.load(inode)?;
+        let node = node.read().unpoisoned();
+        let Some(value) = node.xattrs.get(name.to_bytes()) else {
+            return Err(Error::NoAttribute(inode).into());
+        };
+
+        // A size of zero asks for the size of the value
+        if size == 0 {
+            Ok(GetxattrReply::Count(value.len() as u32))
+        } else if value.len() > size as usize {
+            Err(Error::OutOfRange.into())
+        } else {
+            Ok(GetxattrReply::Value(value.clone()))
+        }
+    }
+
+    fn listxattr(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        let _ = ctx;
+        debug!("listxattr {inode} size={size}");
+        let node = self.load(inode)?;
+        let node = node.read().unpoisoned();
+        // Every name is terminated by a nul byte
+        let mut names = Vec::new();
+        for name in node.xattrs.keys() {
+            names.extend_from_slice(name);
+            names.push(0);
+        }
+
+        // A size of zero asks for the size of the list
+        if size == 0 {
+            Ok(ListxattrReply::Count(names.len() as u32))
+        } else if names.len() > size as usize {
+            Err(Error::OutOfRange.into())
+        } else {
+            Ok(ListxattrReply::Names(names))
+        }
+    }
+
+    fn removexattr(
+        &self,
+        ctx: &fuse_backend_rs::api::filesystem::Context,
+        inode: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<()> {
+        let _ = ctx;
+        debug!("removexattr {inode} {name:?}");
+        let _snapshot = self.snapshot_lock.read().unpoisoned();
+        let node = self.load(inode)?;
+        let mut node = node.write().unpoisoned();
+        if !node.xattrs.contains_key(name.to_bytes()) {
+            return Err(Error::NoAttribute(inode).into());
+        }
+        let attributes = node.attributes();
+        node.update_ctime();
+        let mut transaction = self.transaction();
+        transaction.remove_xattr(inode, name.to_bytes());
+        transaction.attr(&node);
+        if let Err(e) = self.commit(transaction) {
+            node.restore_attributes(attributes);
+            return Err(e);
+        }
+        node.xattrs.remove(name.to_bytes());
+        Ok(())
+    }
+}
+
+/// This struct is just used for logging all requests
+struct LoggingMetricsHook {}
+
+impl MetricsHook for LoggingMetricsHook {
+    fn collect(&self, ih: &fuse_backend_rs::abi::fuse_abi::InHeader) {
+        trace!("Begin request {:?}", Opcode::from(ih.opcode));
+    }
+
+    fn release(&self, oh: Option<&fuse_backend_rs::abi::fuse_abi::OutHeader>) {
+        if let Some(e) = oh {
+            info!("{e:?}");
+        } else {
+            trace!("End request");
+        }
+    }
+}
+
+/// Options that change how the filesystem is mounted
+#[derive(Clone, Debug, Default)]
+pub struct MountOptions {
+    /// Allow other users than the one mounting the filesystem to access it.
+    /// Access is then checked against the owner and permissions of every node.
+    pub allow_other: bool,
+
+    /// The most bytes the content of all files may take up. Writes beyond it fail with ENOSPC.
+    pub max_bytes: Option<u64>,
+
+    /// The most files, folders and symlinks that may exist at the same time
+    pub max_inodes: Option<u64>,
+
+    /// The image the filesystem is loaded from at startup and saved to at unmount.
+    /// The filesystem starts empty if the image does not exist yet.
+    pub image: Option<PathBuf>,
+
+    /// How often the image is saved while the filesystem is mounted.
+    /// It is only saved at unmount if this is None.
+    pub save_interval: Option<Duration>,
+
+    /// When changes recorded in the journal of the image are synced to disk
+    pub journal_sync: SyncPolicy,
+
+    /// The number of threads handling requests. There is one per CPU if this is None.
+    pub threads: Option<NonZeroUsize>,
+
+    /// How long the kernel may cache attributes, entries and missing names
+    pub timeouts: CacheTimeouts,
+}
+
+/// How long the kernel may answer requests from its caches without asking the filesystem.
+/// Changes the kernel did not make itself are invalidated explicitly, so longer timeouts only save requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheTimeouts {
+    /// How long the attributes of a node stay valid
+    pub attr: Duration,
+    /// How long the mapping of a name to a node stays valid
+    pub entry: Duration,
+    /// How long the kernel may remember that a name does not exist
+    pub negative: Duration,
+}
+
+impl Default for CacheTimeouts {
+    fn default() -> Self {
+        CacheTimeouts {
+            attr: Duration::from_secs(1),
+            entry: Duration::from_secs(1),
+            negative: Duration::ZERO,
+        }
+    }
+}
+
+/// When the journal is synced to disk.
+/// Changes are written to the journal before they are acknowledged in any case,
+/// so they survive the filesystem process being killed. Syncing protects them against a crash of the system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every change is synced before it is acknowledged
+    #[default]
+    Always,
+    /// The journal is synced with the first change after this much time passed since the last sync
+    Interval(Duration),
+    /// Syncing is left to the operating system
+    Never,
+}
+
+pub struct ServerSession<'a> {
+    filesystem: Arc<MyFileSystem<'a>>,
+    server: Server<Arc<MyFileSystem<'a>>>,
+    pub session: Arc<RwLock<FuseSession>>,
+    /// Every worker thread reads requests from its own channel
+    channels: Vec<FuseChannel>,
+    image: Option<PathBuf>,
+    save_interval: Option<Duration>,
+}
+
+impl ServerSession<'_> {
+    /// Mounts the filesystem at `mount_point` after restoring it from the image, if there is one.
+    /// An image that can not be loaded is returned as an error before anything is mounted.
+    pub fn new(mount_point: &str, options: MountOptions) -> io::Result<Self> {
+        let mut filesystem = MyFileSystem::new(&options);
+        if let Some(image) = &options.image {
+            filesystem
+                .restore(image, options.journal_sync)
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Could not load image {}: {e}", image.display()),
+                    )
+                })?;
+        }
+        let mount_error = |e| io::Error::other(format!("Could not mount at {mount_point}: {e}"));
+        let session = Arc::new(RwLock::new(
+            FuseSession::new(Path::new(mount_point), "my-fuse", "", false).map_err(mount_error)?,
+        ));
+
+        let threads = options
+            .threads
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get);
+        let channels = {
+            let mut session = session.write().unwrap();
+            // The session always mounts with default_permissions so the kernel checks permissions
+            session.set_allow_other(options.allow_other);
+            session.mount().map_err(mount_error)?;
+            // Notifications are written to the device itself instead of one of the channels
+            let device = session
+                .get_fuse_file()
+                .ok_or_else(|| io::Error::other("The mounted session has no device"))?
+                .try_clone()?;
+            filesystem.notifier = Some(Notifier::new(device));
+            (0..threads)
+                .map(|_| session.new_channel().map_err(mount_error))
+                .collect::<io::Result<_>>()?
+        };
+        let filesystem = Arc::new(filesystem);
+        let server = Server::new(filesystem.clone());
+
+        Ok(Self {
+            filesystem,
+            server,
+            session,
+            channels,
+            image: options.image,
+            save_interval: options.save_interval,
+        })
+    }
+
+    /// Handles requests on all worker threads until the filesystem is unmounted
+    /// and saves the image once all of them stopped
+    pub fn start(&mut self) {
+        let Self {
+            filesystem,
+            server,
+            session,
+            channels,
+            image,
+            save_interval,
+        } = self;
+
+        thread::scope(|scope| {
+            // Dropping the sender stops the thread saving the image periodically
+            let (stop_saving, stopped) = mpsc::channel::<()>();
+            if let (Some(image), Some(interval)) = (&image, *save_interval) {
+                let filesystem = &*filesystem;
+                scope.spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                        image::save(filesystem, image).unwrap_or_else(|e| {
+                            error!("Could not save image {}: {e}", image.display())
+                        });
+                    }
+                });
+            }
+
+            info!("Running fuse with {} threads", channels.len());
+            let workers: Vec<_> = channels
+                .iter_mut()
+                .map(|channel| {
+                    let (server, session) = (&*server, &*session);
+                    scope.spawn(move || {
+                        serve(server, channel);
+                        // Once one worker stops, the others are woken up to stop as well
+                        session
+                            .read()
+                            .unwrap()
+                            .wake()
+                            .unwrap_or_else(|e| error!("{e}"));
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            drop(stop_saving);
+        });
+
+        if let Some(image) = image {
+            image::save(filesystem, image)
+                .unwrap_or_else(|e| error!("Could not save image {}: {e}", image.display()));
+        }
+    }
+}
+
+/// Handles the requests of one channel until the filesystem is unmounted or the session is woken up
+fn serve(server: &Server<Arc<MyFileSystem>>, channel: &mut FuseChannel) {
+    let metrics_hook = LoggingMetricsHook {};
+    loop {
+        match channel.get_request() {
+            Ok(Some((reader, writer))) => {
+                server
+                    .handle_message(reader, writer.into(), None, Some(&metrics_hook))
+                    .unwrap_or_else(|e| {
+                        error!("{e:?}");
+                        0
+                    });
+            }
+            Ok(None) => {
+                info!("Filesystem was unmounted");
+                break;
+            }
+            Err(e) => {
+                error!("Request Error: {e}");
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for ServerSession<'_> {
+    fn drop(&mut self) {
+        info!("Unmounting");
+        {
+            let mut session = self.session.write().unwrap();
+            if let Err(e) = session.umount() {
+                error!("Could not unmount: {e}");
+            }
+        }
+    }
+}
+
+pub mod test_util {
+    use crate::{MountOptions, ServerSession};
+    use fuse_backend_rs::transport::FuseSession;
+
+    use log::info;
+    use std::{
+        path::Path,
+        sync::{Arc, RwLock},
+        thread::{self, JoinHandle},
+    };
+    use tempdir::TempDir;
+
+    pub struct TestFixture {
+        session: Arc<RwLock<FuseSession>>,
+        thread: Option<JoinHandle<()>>,
+        tmp_dir: TempDir,
+    }
+
+    impl Default for TestFixture {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl TestFixture {
+        pub fn new() -> Self {
+            Self::with_options(MountOptions::default())
+        }
+
+        pub fn with_options(options: MountOptions) -> Self {
+            let tmp_dir = TempDir::new("my-fuse").unwrap();
+            let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+            let mut server_session = ServerSession::new(tmp_dir_path.as_str(), options).unwrap();
+
+            let session = server_session.session.clone();
+
+            let thread = thread::spawn(move || {
+                server_session.start();
+            });
+
+            Self {
+                session,
+                thread: Some(thread),
+                tmp_dir,
+            }
+        }
+
+        pub fn path(&self) -> &Path {
+            self.tmp_dir.path()
+        }
+    }
+
+    impl Drop for TestFixture {
+        fn drop(&mut self) {
+            info!("Drop test fixture");
+            {
+                let mut session = self.session.write().unwrap();
+                session.umount().unwrap();
+            }
+            // The server saves its image after it stopped, so it has to be finished here
+            if let Some(thread) = self.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::{
+        CacheTimeouts, InnerNode, Inode, MAX_FILE_SIZE, MountOptions, MyFileSystem, ROOT_INODE,
+        ServerSession, SyncPolicy, journal::Journal, notify::Notifier, test_util::TestFixture,
+    };
+
+    use fuse_backend_rs::{
+        abi::fuse_abi::{CreateIn, FsOptions, SetattrValid, stat64},
+        api::filesystem::{Context, FileSystem, ZeroCopyReader, ZeroCopyWriter},
+        file_traits::FileReadWriteVolatile,
+    };
+    use itertools::Itertools;
+    use std::{
+        ffi::{CStr, CString, OsStr},
+        fs,
+        io::{self, Read, Seek, SeekFrom, Write},
+        os::unix::{
+            self,
+            ffi::OsStrExt,
+            fs::{DirBuilderExt, FileExt, FileTypeExt, MetadataExt, PermissionsExt},
+            io::AsRawFd,
+            process::CommandExt,
+        },
+        path::Path,
+        process::{Command, Stdio},
+        sync::{atomic::Ordering, mpsc},
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    #[test_log::test]
+    fn mount_filesystem() {
+        // Arrange
+        let fixture = TestFixture::new();
+
+        // Act
+
+        let dir_content = fs::read_dir(fixture.path()).unwrap();
+
+        // Assert
+        assert_eq!(dir_content.count(), 0);
+    }
+
+    #[test_log::test]
+    fn write_file() {
+        // Arrange
+        let fixture = TestFixture::new();
+
+        // Act
+
+        fs::write(fixture.path().join("test"), "test").unwrap();
+
+        // Assert
+
+        let dir_content = fs::read_dir(fixture.path()).unwrap();
+        assert_eq!(dir_content.count(), 1);
+    }
+
+    #[test_log::test]
+    fn read_file() {
+        // Arrange
+        let fixture = TestFixture::new();
+        fs::write(fixture.path().join("test"), "test").unwrap();
+
+        // Act
+
+        let data = fs::read(fixture.path().join("test")).unwrap();
+
+        // Assert
+
+        let dir_content = fs::read_dir(fixture.path()).unwrap();
+        assert_eq!(dir_content.count(), 1);
+
+        let content = String::from_utf8(data).unwrap();
+
+        assert_eq!(content.as_str(), "test");
+    }
+
+    #[test_log::test]
+    fn sparse_file() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        let mut file = fs::File::create(&path).unwrap();
+
+        // Act
+
+        file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        file.write_all(b"test").unwrap();
+        file.set_len(1024 * 1024 + 2).unwrap();
+        drop(file);
+
+        // Assert
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 1024 * 1024 + 2);
+        assert!(data[..1024 * 1024].iter().all(|&b| b == 0));
+        assert_eq!(&data[1024 * 1024..], b"te");
+    }
+
+    #[test_log::test]
+    fn fallocate_and_seek_holes() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(fixture.path().join("test"))
+            .unwrap();
+        file.set_len(1024 * 1024).unwrap();
+        file.write_all_at(b"test", 64 * 1024).unwrap();
+        let fd = file.as_raw_fd();
+        let fallocate = |mode, offset, len| unsafe { libc::fallocate(fd, mode, offset, len) };
+
+        // Act
+
+        let allocate = fallocate(0, 512 * 1024, 8192);
+        let punch = fallocate(
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            64 * 1024,
+            4096,
+        );
+        let punch_without_keep_size = fallocate(libc::FALLOC_FL_PUNCH_HOLE, 0, 4096);
+        let punch_error = io::Error::last_os_error();
+        let grow = fallocate(libc::FALLOC_FL_ZERO_RANGE, 1024 * 1024, 4096);
+        let empty = fallocate(0, 0, 0);
+        let empty_error = io::Error::last_os_error();
+
+        // Assert
+
+        assert_eq!(allocate, 0);
+        assert_eq!(punch, 0);
+        assert_eq!(punch_without_keep_size, -1);
+        assert_eq!(punch_error.raw_os_error(), Some(libc::EOPNOTSUPP));
+        assert_eq!(grow, 0);
+        assert_eq!(empty, -1);
+        assert_eq!(empty_error.raw_os_error(), Some(libc::EINVAL));
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.len(), 1024 * 1024 + 4096);
+        assert_eq!(metadata.blocks(), 24);
+        let data = unsafe { libc::lseek(fd, 0, libc::SEEK_DATA) };
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        assert_eq!(data, 512 * 1024);
+        assert_eq!(hole, 512 * 1024 + 8192);
+        let mut buf = [1u8; 4];
+        file.read_exact_at(&mut buf, 64 * 1024).unwrap();
+        assert_eq!(buf, [0; 4]);
+    }
+
+    #[test_log::test]
+    fn mkdir() {
+        // Arrange
+        let fixture = TestFixture::new();
+
+        // Act
+
+        fs::create_dir(fixture.path().join("test")).unwrap();
+
+        // Assert
+
+        let dir_content = fs::read_dir(fixture.path())
+            .unwrap()
+            .flat_map(|x| x.ok())
+            .collect_vec();
+        assert_eq!(dir_content.len(), 1);
+
+        let dir = dir_content.first().unwrap();
+        let filename = dir.file_name();
+        let name = filename.to_str().unwrap();
+
+        assert_eq!(name, "test");
+    }
+
+    #[test_log::test]
+    fn rmdir() {
+        // Arrange
+        let fixture = TestFixture::new();
+        fs::create_dir(fixture.path().join("test")).unwrap();
+
+        // Act
+
+        fs::remove_dir(fixture.path().join("test")).unwrap();
+
+        // Assert
+
+        let dir_content = fs::read_dir(fixture.path())
+            .unwrap()
+            .flat_map(|x| x.ok())
+            .collect_vec();
+        assert_eq!(dir_content.len(), 0);
+    }
+
+    #[test_log::test]
+    fn rmdir_and_unlink_errors() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path();
+        fs::create_dir_all(path.join("folder/sub")).unwrap();
+        fs::write(path.join("file"), "test").unwrap();
+
+        // Act
+
+        let not_empty = fs::remove_dir(path.join("folder"));
+        let not_a_folder = fs::remove_dir(path.join("file"));
+        let is_a_folder = fs::remove_file(path.join("folder/sub"));
+        let missing = fs::remove_dir(path.join("missing"));
+
+        // Assert
+
+        assert_eq!(not_empty.unwrap_err().raw_os_error(), Some(libc::ENOTEMPTY));
+        assert_eq!(
+            not_a_folder.unwrap_err().raw_os_error(),
+            Some(libc::ENOTDIR)
+        );
+        assert_eq!(is_a_folder.unwrap_err().raw_os_error(), Some(libc::EISDIR));
+        assert_eq!(missing.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        assert!(path.join("folder/sub").is_dir());
+        assert_eq!(fs::read_to_string(path.join("file")).unwrap(), "test");
+        fs::remove_dir_all(path.join("folder")).unwrap();
+        assert!(!path.join("folder").exists());
+    }
+
+    #[test_log::test]
+    fn removed_inodes_are_reused() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let folder = fs.mkdir(&ctx, ROOT_INODE, c"folder", 0o755, 0).unwrap();
+        let file = fs
+            .mknod(&ctx, folder.inode, c"file", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+
+        // Act
+
+        let not_empty = fs.rmdir(&ctx, ROOT_INODE, c"folder");
+        fs.unlink(&ctx, folder.inode, c"file").unwrap();
+        fs.rmdir(&ctx, ROOT_INODE, c"folder").unwrap();
+        // The kernel forgets the removed nodes, after which nothing refers to them anymore
+        fs.forget(&ctx, file.inode, 1);
+        fs.forget(&ctx, folder.inode, 1);
+        let first = fs.mkdir(&ctx, ROOT_INODE, c"first", 0o755, 0).unwrap();
+        let second = fs.mkdir(&ctx, ROOT_INODE, c"second", 0o755, 0).unwrap();
+
+        // Assert
+
+        assert_eq!(not_empty.unwrap_err().raw_os_error(), Some(libc::ENOTEMPTY));
+        let mut reused = [first.inode, second.inode];
+        reused.sort();
+        let mut removed = [folder.inode, file.inode];
+        removed.sort();
+        assert_eq!(reused, removed);
+        assert_eq!(fs.used_inodes.load(Ordering::Acquire), 3);
+    }
+
+    #[test_log::test]
+    fn generation_changes_on_reuse() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let first = fs
+            .mknod(&ctx, ROOT_INODE, c"test", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let mut entries = vec![first];
+
+        // Act
+
+        for _ in 0..3 {
+            let last = entries.last().unwrap();
+            fs.unlink(&ctx, ROOT_INODE, c"test").unwrap();
+            fs.forget(&ctx, last.inode, 1);
+            let entry = fs
+                .mknod(&ctx, ROOT_INODE, c"test", libc::S_IFREG | 0o644, 0, 0)
+                .unwrap();
+            entries.push(entry);
+        }
+
+        // Assert
+
+        for (generation, entry) in entries.iter().enumerate() {
+            assert_eq!(entry.inode, first.inode);
+            assert_eq!(entry.generation, generation as u64);
+        }
+        let looked_up = fs.lookup(&ctx, ROOT_INODE, c"test").unwrap();
+        assert_eq!(looked_up.generation, 3);
+    }
+
+    #[test_log::test]
+    fn generations_survive_restore() {
+        // Arrange
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        let ctx = Context::new();
+        let mut fs = MyFileSystem::new(&MountOptions::default());
+        fs.restore(&image, SyncPolicy::Never).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let kept = fs.mkdir(&ctx, ROOT_INODE, c"kept", 0o755, 0).unwrap();
+        let removed = fs.mkdir(&ctx, ROOT_INODE, c"removed", 0o755, 0).unwrap();
+        fs.rmdir(&ctx, ROOT_INODE, c"kept").unwrap();
+        fs.forget(&ctx, kept.inode, 1);
+        fs.mkdir(&ctx, ROOT_INODE, c"kept", 0o755, 0).unwrap();
+        // The removed folder is only recorded in the journal
+        crate::image::save(&fs, &image).unwrap();
+        fs.rmdir(&ctx, ROOT_INODE, c"removed").unwrap();
+        fs.forget(&ctx, removed.inode, 1);
+
+        // Act
+
+        let mut restored = MyFileSystem::new(&MountOptions::default());
+        restored.restore(&image, SyncPolicy::Never).unwrap();
+        restored.init(FsOptions::empty()).unwrap();
+        let recreated = restored
+            .mkdir(&ctx, ROOT_INODE, c"recreated", 0o755, 0)
+            .unwrap();
+
+        // Assert
+
+        let kept = restored.lookup(&ctx, ROOT_INODE, c"kept").unwrap();
+        assert_eq!(kept.generation, 1);
+        assert_eq!(recreated.inode, removed.inode);
+        assert_eq!(recreated.generation, 1);
+    }
+
+    #[test_log::test]
+    fn restore_after_changing_unlinked_file() {
+        // Arrange
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        let ctx = Context::new();
+        let mut fs = MyFileSystem::new(&MountOptions::default());
+        fs.restore(&image, SyncPolicy::Never).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let (entry, handle, ..) = fs
+            .create(
+                &ctx,
+                ROOT_INODE,
+                c"temp",
+                CreateIn {
+                    flags: libc::O_RDWR as u32,
+                    mode: 0o644,
+                    umask: 0,
+                    fuse_flags: 0,
+                },
+            )
+            .unwrap();
+        let handle = handle.unwrap();
+        fs.unlink(&ctx, ROOT_INODE, c"temp").unwrap();
+        // The unlinked file is left out of the image, but it is still open and changes afterwards
+        crate::image::save(&fs, &image).unwrap();
+        let mut request = RequestData(io::Cursor::new(b"data".to_vec()));
+        fs.write(
+            &ctx,
+            entry.inode,
+            handle,
+            &mut request,
+            4,
+            0,
+            None,
+            false,
+            0,
+            0,
+        )
+        .unwrap();
+        fs.setxattr(&ctx, entry.inode, c"user.test", b"value", 0)
+            .unwrap();
+        // SAFETY: stat64 only consists of integers, for which zero is a valid value
+        let mut attr: stat64 = unsafe { std::mem::zeroed() };
+        attr.st_size = 2;
+        fs.setattr(&ctx, entry.inode, attr, Some(handle), SetattrValid::SIZE)
+            .unwrap();
+
+        // Act
+
+        let mut restored = MyFileSystem::new(&MountOptions::default());
+        let restore = restored.restore(&image, SyncPolicy::Never);
+
+        // Assert
+
+        restore.unwrap();
+        restored.init(FsOptions::empty()).unwrap();
+        let lookup = restored.lookup(&ctx, ROOT_INODE, c"temp");
+        assert_eq!(lookup.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        let recreated = restored
+            .mknod(&ctx, ROOT_INODE, c"new", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        assert_eq!(recreated.inode, entry.inode);
+        assert_eq!(recreated.attr.st_size, 0);
+    }
+
+    #[test_log::test]
+    fn restore_after_interrupted_save() {
+        // Arrange
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        let ctx = Context::new();
+        let mut fs = MyFileSystem::new(&MountOptions::default());
+        fs.restore(&image, SyncPolicy::Never).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        crate::image::save(&fs, &image).unwrap();
+        fs.mkdir(&ctx, ROOT_INODE, c"before", 0o755, 0).unwrap();
+        // The save took its snapshot, but died before the image was written
+        fs.journal.as_ref().unwrap().rotate().unwrap();
+        fs.mkdir(&ctx, ROOT_INODE, c"after", 0o755, 0).unwrap();
+
+        // Act
+
+        let mut restored = MyFileSystem::new(&MountOptions::default());
+        restored.restore(&image, SyncPolicy::Never).unwrap();
+        restored.init(FsOptions::empty()).unwrap();
+        restored
+            .mkdir(&ctx, ROOT_INODE, c"restored", 0o755, 0)
+            .unwrap();
+        let mut again = MyFileSystem::new(&MountOptions::default());
+        again.restore(&image, SyncPolicy::Never).unwrap();
+
+        // Assert
+
+        for name in [c"before", c"after", c"restored"] {
+            again.lookup(&ctx, ROOT_INODE, name).unwrap();
+        }
+        assert!(!image_dir.path().join("image.journal.prev").exists());
+    }
+
+    #[test_log::test]
+    fn restore_after_failed_saves() {
+        // Arrange
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        let ctx = Context::new();
+        let mut fs = MyFileSystem::new(&MountOptions::default());
+        fs.restore(&image, SyncPolicy::Never).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        fs.mkdir(&ctx, ROOT_INODE, c"first", 0o755, 0).unwrap();
+        crate::image::save(&fs, &image).unwrap();
+        // The image can not be written while its temporary file is a folder
+        let temp = image_dir.path().join("image.tmp");
+        std::fs::create_dir(&temp).unwrap();
+        fs.mkdir(&ctx, ROOT_INODE, c"second", 0o755, 0).unwrap();
+        crate::image::save(&fs, &image).unwrap_err();
+        fs.mkdir(&ctx, ROOT_INODE, c"third", 0o755, 0).unwrap();
+        crate::image::save(&fs, &image).unwrap_err();
+        fs.mkdir(&ctx, ROOT_INODE, c"fourth", 0o755, 0).unwrap();
+        std::fs::remove_dir(&temp).unwrap();
+
+        // Act
+
+        let mut restored = MyFileSystem::new(&MountOptions::default());
+        let restore = restored.restore(&image, SyncPolicy::Never);
+
+        // Assert
+
+        restore.unwrap();
+        for name in [c"first", c"second", c"third", c"fourth"] {
+            restored.lookup(&ctx, ROOT_INODE, name).unwrap();
+        }
+    }
+
+    #[test_log::test]
+    fn failed_journal_write_changes_nothing() {
+        // Arrange
+        let mut fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let folder = fs.mkdir(&ctx, ROOT_INODE, c"folder", 0o755, 0).unwrap();
+        let create_in = |flags: i32| CreateIn {
+            flags: flags as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (file, handle, ..) = fs
+            .create(&ctx, ROOT_INODE, c"file", create_in(libc::O_RDWR))
+            .unwrap();
+        let handle = handle.unwrap();
+        let mut request = RequestData(io::Cursor::new(b"data".to_vec()));
+        fs.write(
+            &ctx,
+            file.inode,
+            handle,
+            &mut request,
+            4,
+            0,
+            None,
+            false,
+            0,
+            0,
+        )
+        .unwrap();
+        fs.setxattr(&ctx, file.inode, c"user.test", b"value", 0)
+            .unwrap();
+        let target = fs
+            .mknod(&ctx, ROOT_INODE, c"target", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        // Everything the operations could change, with the cookies of the entries
+        let state = |fs: &MyFileSystem| {
+            let mut state = Vec::new();
+            for parent in [ROOT_INODE, folder.inode] {
+                let (dir_handle, _) = fs.opendir(&ctx, parent, 0).unwrap();
+                fs.readdir(&ctx, parent, dir_handle.unwrap(), 4096, 0, &mut |entry| {
+                    let (attr, _) = fs.getattr(&ctx, entry.ino, None).unwrap();
+                    state.push(format!(
+                        "{} {:?} {} {:o} {} {} {}.{} {}.{}",
+                        entry.offset,
+                        OsStr::from_bytes(entry.name),
+                        attr.st_nlink,
+                        attr.st_mode,
+                        attr.st_size,
+                        attr.st_blocks,
+                        attr.st_mtime,
+                        attr.st_mtime_nsec,
+                        attr.st_ctime,
+                        attr.st_ctime_nsec,
+                    ));
+                    Ok(1)
+                })
+                .unwrap();
+            }
+            let mut reply = ReplyData(Vec::new());
+            fs.read(&ctx, file.inode, handle, &mut reply, 100, 0, None, 0)
+                .unwrap();
+            let node = fs.load(file.inode).unwrap();
+            let xattrs = node.read().unwrap().xattrs.clone();
+            state.push(format!("{:?} {xattrs:?}", reply.0));
+            state.push(format!(
+                "{} {}",
+                fs.used_inodes.load(Ordering::Acquire),
+                fs.used_bytes.load(Ordering::Acquire)
+            ));
+            state
+        };
+        let before = state(&fs);
+        fs.journal = Some(Journal::full());
+        // SAFETY: stat64 only consists of integers, for which zero is a valid value
+        let mut attr: stat64 = unsafe { std::mem::zeroed() };
+        attr.st_size = 1;
+
+        // Act
+
+        let results = [
+            fs.mkdir(&ctx, ROOT_INODE, c"new", 0o755, 0).map(drop),
+            fs.mknod(&ctx, ROOT_INODE, c"new", libc::S_IFREG | 0o644, 0, 0)
+                .map(drop),
+            fs.symlink(&ctx, c"file", ROOT_INODE, c"new").map(drop),
+            fs.create(&ctx, ROOT_INODE, c"new", create_in(libc::O_RDWR))
+                .map(drop),
+            fs.link(&ctx, file.inode, folder.inode, c"link").map(drop),
+            fs.unlink(&ctx, ROOT_INODE, c"target"),
+            fs.rmdir(&ctx, ROOT_INODE, c"folder"),
+            fs.rename(&ctx, ROOT_INODE, c"file", ROOT_INODE, c"target", 0),
+            fs.rename(&ctx, ROOT_INODE, c"file", folder.inode, c"moved", 0),
+            fs.open(&ctx, file.inode, (libc::O_RDWR | libc::O_TRUNC) as u32, 0)
+                .map(drop),
+            fs.setattr(&ctx, file.inode, attr, Some(handle), SetattrValid::SIZE)
+                .map(drop),
+            fs.write(
+                &ctx,
+                file.inode,
+                handle,
+                &mut RequestData(io::Cursor::new(b"more".to_vec())),
+                4,
+                4,
+                None,
+                false,
+                0,
+                0,
+            )
+            .map(drop),
+            fs.fallocate(&ctx, file.inode, handle, 0, 0, 8192),
+            fs.setxattr(&ctx, file.inode, c"user.other", b"value", 0),
+            fs.removexattr(&ctx, file.inode, c"user.test"),
+        ];
+
+        // Assert
+
+        for result in results {
+            assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        }
+        assert_eq!(state(&fs), before);
+        // The inodes of the nodes that were never linked are free again
+        fs.journal = None;
+        let new = fs
+            .mknod(&ctx, ROOT_INODE, c"new", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        assert_eq!(new.inode, target.inode + 1);
+    }
+
+    #[test_log::test]
+    fn symlink() {
+        // Arrange
+        let fixture = TestFixture::new();
+        fs::write(fixture.path().join("target"), "test").unwrap();
+
+        // Act
+
+        unix::fs::symlink("target", fixture.path().join("link")).unwrap();
+
+        // Assert
+
+        let link = fs::read_link(fixture.path().join("link")).unwrap();
+        assert_eq!(link.to_str().unwrap(), "target");
+
+        let metadata = fs::symlink_metadata(fixture.path().join("link")).unwrap();
+        assert!(metadata.file_type().is_symlink());
+
+        let data = fs::read(fixture.path().join("link")).unwrap();
+        assert_eq!(String::from_utf8(data).unwrap().as_str(), "test");
+    }
+
+    #[test_log::test]
+    fn readdir_symlink() {
+        // Arrange
+        let fixture = TestFixture::new();
+        unix::fs::symlink("/does/not/exist", fixture.path().join("dangling")).unwrap();
+
+        // Act
+
+        let dir_content = fs::read_dir(fixture.path())
+            .unwrap()
+            .flat_map(|x| x.ok())
+            .collect_vec();
+
+        // Assert
+
+        assert_eq!(dir_content.len(), 1);
+        let dir = dir_content.first().unwrap();
+        assert!(dir.file_type().unwrap().is_symlink());
+        assert_eq!(
+            fs::read_link(dir.path()).unwrap().to_str().unwrap(),
+            "/does/not/exist"
+        );
+    }
+
+    #[test_log::test]
+    fn hard_link() {
+        // Arrange
+        let fixture = TestFixture::new();
+        fs::write(fixture.path().join("test"), "test").unwrap();
+
+        // Act
+
+        fs::hard_link(fixture.path().join("test"), fixture.path().join("link")).unwrap();
+
+        // Assert
+
+        let metadata = fs::metadata(fixture.path().join("link")).unwrap();
+        assert_eq!(metadata.nlink(), 2);
+        assert_eq!(
+            metadata.ino(),
+            fs::metadata(fixture.path().join("test")).unwrap().ino()
+        );
+
+        fs::remove_file(fixture.path().join("test")).unwrap();
+
+        let metadata = fs::metadata(fixture.path().join("link")).unwrap();
+        assert_eq!(metadata.nlink(), 1);
+        let data = fs::read(fixture.path().join("link")).unwrap();
+        assert_eq!(String::from_utf8(data).unwrap().as_str(), "test");
+    }
+
+    #[test_log::test]
+    fn folder_link_count() {
+        // Arrange
+        let fixture = TestFixture::new();
+        fs::create_dir(fixture.path().join("parent")).unwrap();
+
+        // Act
+
+        fs::create_dir(fixture.path().join("parent/a")).unwrap();
+        fs::create_dir(fixture.path().join("parent/b")).unwrap();
+        fs::write(fixture.path().join("parent/file"), "test").unwrap();
+
+        // Assert
+
+        let metadata = fs::metadata(fixture.path().join("parent")).unwrap();
+        assert_eq!(metadata.nlink(), 4);
+
+        fs::remove_dir(fixture.path().join("parent/a")).unwrap();
+        fs::rename(fixture.path().join("parent/b"), fixture.path().join("b")).unwrap();
+
+        let metadata = fs::metadata(fixture.path().join("parent")).unwrap();
+        assert_eq!(metadata.nlink(), 2);
+        let metadata = fs::metadata(fixture.path()).unwrap();
+        assert_eq!(metadata.nlink(), 4);
+    }
+
+    #[test_log::test]
+    fn rename_replaces_target() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path();
+        fs::write(path.join("a"), "a").unwrap();
+        fs::write(path.join("b"), "b").unwrap();
+        fs::create_dir_all(path.join("folder/sub")).unwrap();
+        fs::create_dir(path.join("empty")).unwrap();
+        fs::create_dir(path.join("other")).unwrap();
+
+        // Act
+
+        fs::rename(path.join("a"), path.join("b")).unwrap();
+        fs::rename(path.join("other"), path.join("empty")).unwrap();
+
+        // Assert
+
+        assert_eq!(fs::read_to_string(path.join("b")).unwrap(), "a");
+        assert!(!path.join("a").exists());
+        assert!(!path.join("other").exists());
+        assert_eq!(fs::metadata(path).unwrap().nlink(), 4);
+        let error = |from: &str, to: &str| {
+            fs::rename(path.join(from), path.join(to))
+                .unwrap_err()
+                .raw_os_error()
+        };
+        assert_eq!(error("empty", "folder"), Some(libc::ENOTEMPTY));
+        assert_eq!(error("empty", "b"), Some(libc::ENOTDIR));
+        assert_eq!(error("b", "empty"), Some(libc::EISDIR));
+        assert_eq!(error("folder", "folder/sub/moved"), Some(libc::EINVAL));
+        assert_eq!(error("folder/sub", "folder/sub/moved"), Some(libc::EINVAL));
+        assert_eq!(error("missing", "moved"), Some(libc::ENOENT));
+    }
+
+    #[test_log::test]
+    fn rename_flags() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path();
+        fs::write(path.join("file"), "file").unwrap();
+        fs::create_dir_all(path.join("folder/sub")).unwrap();
+        fs::write(path.join("folder/other"), "other").unwrap();
+
+        // Act
+
+        let noreplace = rename_with_flags(
+            &path.join("file"),
+            &path.join("folder/other"),
+            libc::RENAME_NOREPLACE,
+        );
+        rename_with_flags(
+            &path.join("file"),
+            &path.join("folder/sub"),
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap();
+        let exchange_missing = rename_with_flags(
+            &path.join("sub"),
+            &path.join("missing"),
+            libc::RENAME_EXCHANGE,
+        );
+        rename_with_flags(
+            &path.join("folder/other"),
+            &path.join("moved"),
+            libc::RENAME_WHITEOUT,
+        )
+        .unwrap();
+
+        // Assert
+
+        assert_eq!(noreplace.unwrap_err().raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(
+            exchange_missing.unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert!(fs::metadata(path.join("file")).unwrap().is_dir());
+        assert_eq!(fs::read_to_string(path.join("folder/sub")).unwrap(), "file");
+        // The folder took the place of the file, so the root gained a ".." link
+        assert_eq!(fs::metadata(path).unwrap().nlink(), 4);
+        assert_eq!(fs::metadata(path.join("folder")).unwrap().nlink(), 2);
+        assert_eq!(fs::read_to_string(path.join("moved")).unwrap(), "other");
+        let whiteout = fs::symlink_metadata(path.join("folder/other")).unwrap();
+        assert!(whiteout.file_type().is_char_device());
+        assert_eq!(whiteout.rdev(), 0);
+    }
+
+    #[test_log::test]
+    fn concurrent_cross_directory_renames() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            threads: std::num::NonZeroUsize::new(4),
+            ..Default::default()
+        });
+        let path = fixture.path();
+        fs::create_dir(path.join("a")).unwrap();
+        fs::create_dir(path.join("b")).unwrap();
+        fs::write(path.join("a/from_a"), "a").unwrap();
+        fs::write(path.join("b/from_b"), "b").unwrap();
+
+        // Act
+
+        thread::scope(|scope| {
+            for (from, to, name) in [("a", "b", "from_a"), ("b", "a", "from_b")] {
+                scope.spawn(move || {
+                    for _ in 0..200 {
+                        fs::rename(path.join(from).join(name), path.join(to).join(name)).unwrap();
+                        fs::rename(path.join(to).join(name), path.join(from).join(name)).unwrap();
+                    }
+                });
+            }
+        });
+
+        // Assert
+
+        assert_eq!(fs::read_to_string(path.join("a/from_a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(path.join("b/from_b")).unwrap(), "b");
+    }
+
+    #[test_log::test]
+    fn create_existing_names() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let file = fs
+            .mknod(&ctx, ROOT_INODE, c"file", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let folder = fs.mkdir(&ctx, ROOT_INODE, c"folder", 0o755, 0).unwrap();
+        let create = |flags: i32| CreateIn {
+            flags: (libc::O_CREAT | libc::O_RDWR | flags) as u32,
+            mode: libc::S_IFREG | 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+
+        // Act
+
+        let mkdir_existing = fs.mkdir(&ctx, ROOT_INODE, c"file", 0o755, 0);
+        let mknod_existing = fs.mknod(&ctx, ROOT_INODE, c"folder", libc::S_IFREG | 0o644, 0, 0);
+        let create_exclusive = fs.create(&ctx, ROOT_INODE, c"file", create(libc::O_EXCL));
+        let create_folder = fs.create(&ctx, ROOT_INODE, c"folder", create(0));
+        let create_existing = fs.create(&ctx, ROOT_INODE, c"file", create(0));
+        let create_new = fs.create(&ctx, ROOT_INODE, c"new", create(libc::O_EXCL));
+
+        // Assert
+
+        let errno = |error: io::Error| error.raw_os_error();
+        assert_eq!(errno(mkdir_existing.unwrap_err()), Some(libc::EEXIST));
+        assert_eq!(errno(mknod_existing.unwrap_err()), Some(libc::EEXIST));
+        assert_eq!(errno(create_exclusive.unwrap_err()), Some(libc::EEXIST));
+        assert_eq!(errno(create_folder.unwrap_err()), Some(libc::EISDIR));
+        let (entry, handle, _, _) = create_existing.unwrap();
+        assert_eq!(entry.inode, file.inode);
+        assert!(handle.is_some());
+        let (entry, _, _, _) = create_new.unwrap();
+        assert_eq!(
+            fs.lookup(&ctx, ROOT_INODE, c"new").unwrap().inode,
+            entry.inode
+        );
+        assert_eq!(
+            fs.lookup(&ctx, ROOT_INODE, c"file").unwrap().inode,
+            file.inode
+        );
+        assert_eq!(
+            fs.lookup(&ctx, ROOT_INODE, c"folder").unwrap().inode,
+            folder.inode
+        );
+    }
+
+    #[test_log::test]
+    fn concurrent_creators() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            threads: std::num::NonZeroUsize::new(4),
+            ..Default::default()
+        });
+        let path = fixture.path();
+
+        // Act
+
+        let wins: Vec<Vec<(bool, bool)>> = thread::scope(|scope| {
+            let creators: Vec<_> = (0..4)
+                .map(|i| {
+                    scope.spawn(move || {
+                        (0..100)
+                            .map(|j| {
+                                let file = fs::File::options()
+                                    .write(true)
+                                    .create_new(true)
+                                    .open(path.join(format!("file_{j}")));
+                                if let Ok(mut file) = file.as_ref() {
+                                    file.write_all(format!("{i}").as_bytes()).unwrap();
+                                }
+                                let folder = fs::create_dir(path.join(format!("folder_{j}")));
+                                for result in [file.as_ref().map(drop), folder.as_ref().map(drop)] {
+                                    if let Err(error) = result {
+                                        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+                                    }
+                                }
+                                (file.is_ok(), folder.is_ok())
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            creators
+                .into_iter()
+                .map(|creator| creator.join().unwrap())
+                .collect()
+        });
+
+        // Assert
+
+        for j in 0..100 {
+            let file_winners: Vec<_> = (0..4).filter(|&i| wins[i][j].0).collect();
+            let folder_winners = wins.iter().filter(|thread| thread[j].1).count();
+            assert_eq!(file_winners.len(), 1);
+            assert_eq!(folder_winners, 1);
+            let content = fs::read_to_string(path.join(format!("file_{j}"))).unwrap();
+            assert_eq!(content, file_winners[0].to_string());
+        }
+        assert_eq!(fs::read_dir(path).unwrap().count(), 200);
+    }
+
+    #[test_log::test]
+    fn open_truncates() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "hello world").unwrap();
+
+        // Act
+
+        let mut file = fs::File::options()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let truncated = file.metadata().unwrap().len();
+        file.write_all(b"hi").unwrap();
+        drop(file);
+
+        // Assert
+
+        assert_eq!(truncated, 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hi");
+    }
+
+    #[test_log::test]
+    fn append_handles() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let create = CreateIn {
+            flags: (libc::O_CREAT | libc::O_WRONLY | libc::O_APPEND) as u32,
+            mode: libc::S_IFREG | 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, appending, _, _) = fs.create(&ctx, ROOT_INODE, c"log", create).unwrap();
+        let (writing, _, _) = fs
+            .open(&ctx, entry.inode, libc::O_WRONLY as u32, 0)
+            .unwrap();
+        let write = |handle: u64, data: &[u8], offset: u64| {
+            let mut request = RequestData(io::Cursor::new(data.to_vec()));
+            let size = data.len() as u32;
+            fs.write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut request,
+                size,
+                offset,
+                None,
+                false,
+                0,
+                0,
+            )
+            .unwrap();
+        };
+
+        // Act
+
+        write(writing.unwrap(), b"first ", 0);
+        // The offset of an appending handle is ignored
+        write(appending.unwrap(), b"second", 0);
+        write(writing.unwrap(), b"F", 0);
+
+        // Assert
+
+        let mut reply = ReplyData(Vec::new());
+        fs.read(
+            &ctx,
+            entry.inode,
+            writing.unwrap(),
+            &mut reply,
+            100,
+            0,
+            None,
+            0,
+        )
+        .unwrap();
+        assert_eq!(reply.0, b"First second");
+    }
+
+    #[test_log::test]
+    fn handles_are_validated() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let file = fs
+            .mknod(&ctx, ROOT_INODE, c"file", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let (file_handle, _, _) = fs.open(&ctx, file.inode, libc::O_RDWR as u32, 0).unwrap();
+        let file_handle = file_handle.unwrap();
+        let (dir_handle, _) = fs.opendir(&ctx, ROOT_INODE, 0).unwrap();
+        let dir_handle = dir_handle.unwrap();
+        let read = |inode, handle| {
+            fs.read(
+                &ctx,
+                inode,
+                handle,
+                &mut ReplyData(Vec::new()),
+                10,
+                0,
+                None,
+                0,
+            )
+        };
+
+        // Act
+
+        let read_open = read(file.inode, file_handle);
+        let read_unknown = read(file.inode, file_handle + 100);
+        let read_other_inode = read(file.inode, dir_handle);
+        let mut names = Vec::new();
+        let readdir_open = fs.readdir(&ctx, ROOT_INODE, dir_handle, 4096, 0, &mut |entry| {
+            names.push(entry.name.to_vec());
+            Ok(1)
+        });
+        let readdir_file_handle =
+            fs.readdir(&ctx, ROOT_INODE, file_handle, 4096, 0, &mut |_| Ok(1));
+        let release = fs.release(&ctx, file.inode, 0, file_handle, false, false, None);
+        let read_released = read(file.inode, file_handle);
+        let releasedir = fs.releasedir(&ctx, ROOT_INODE, 0, dir_handle);
+        let releasedir_again = fs.releasedir(&ctx, ROOT_INODE, 0, dir_handle);
+
+        // Assert
+
+        let errno = |error: io::Error| error.raw_os_error();
+        assert_eq!(read_open.unwrap(), 0);
+        assert_eq!(errno(read_unknown.unwrap_err()), Some(libc::EBADF));
+        assert_eq!(errno(read_other_inode.unwrap_err()), Some(libc::EBADF));
+        readdir_open.unwrap();
+        assert_eq!(names, [&b"."[..], b"..", b"file"]);
+        assert_eq!(errno(readdir_file_handle.unwrap_err()), Some(libc::EBADF));
+        release.unwrap();
+        assert_eq!(errno(read_released.unwrap_err()), Some(libc::EBADF));
+        releasedir.unwrap();
+        assert_eq!(errno(releasedir_again.unwrap_err()), Some(libc::EBADF));
+    }
+
+    #[test_log::test]
+    fn readdir_cookies() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        for i in 0..10 {
+            let name = CString::new(format!("file_{i}")).unwrap();
+            fs.mknod(&ctx, ROOT_INODE, &name, libc::S_IFREG | 0o644, 0, 0)
+                .unwrap();
+        }
+        let (handle, _) = fs.opendir(&ctx, ROOT_INODE, 0).unwrap();
+        let handle = handle.unwrap();
+        // Only `fits` entries fit into the buffer of one request
+        let readdir = |offset: u64, fits: usize| {
+            let mut entries = Vec::new();
+            fs.readdir(&ctx, ROOT_INODE, handle, 4096, offset, &mut |entry| {
+                if entries.len() == fits {
+                    return Ok(0);
+                }
+                entries.push((
+                    entry.offset,
+                    String::from_utf8(entry.name.to_vec()).unwrap(),
+                ));
+                Ok(1)
+            })
+            .unwrap();
+            entries
+        };
+
+        // Act
+
+        let first = readdir(0, 6);
+        fs.unlink(&ctx, ROOT_INODE, c"file_1").unwrap();
+        fs.unlink(&ctx, ROOT_INODE, c"file_7").unwrap();
+        fs.mknod(&ctx, ROOT_INODE, c"file_0a", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let rest = readdir(first.last().unwrap().0, usize::MAX);
+        let again = readdir(first[2].0, 1);
+
+        // Assert
+
+        let names = |entries: &[(u64, String)]| -> Vec<String> {
+            entries.iter().map(|(_, name)| name.clone()).collect()
+        };
+        assert_eq!(
+            names(&first),
+            [".", "..", "file_0", "file_1", "file_2", "file_3"]
+        );
+        assert_eq!(
+            names(&rest),
+            ["file_4", "file_5", "file_6", "file_8", "file_9", "file_0a"]
+        );
+        assert_eq!(names(&again), ["file_2"]);
+        let cookies: Vec<_> = first
+            .iter()
+            .chain(&rest)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+        assert!(cookies.is_sorted_by(|a, b| a < b));
+    }
+
+    #[test_log::test]
+    fn readdirplus_counts_lookups() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let file_0 = fs
+            .mknod(&ctx, ROOT_INODE, c"file_0", libc::S_IFREG | 0o640, 0, 0)
+            .unwrap()
+            .inode;
+        let file_1 = fs
+            .mknod(&ctx, ROOT_INODE, c"file_1", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap()
+            .inode;
+        // The kernel forgets the lookups of mknod, only readdirplus should keep the files alive
+        fs.forget(&ctx, file_0, 1);
+        fs.forget(&ctx, file_1, 1);
+        let (handle, _) = fs.opendir(&ctx, ROOT_INODE, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut entries = Vec::new();
+
+        // Act
+
+        // Only ".", ".." and file_0 fit into the reply
+        let readdirplus = fs.readdirplus(
+            &ctx,
+            ROOT_INODE,
+            handle,
+            4096,
+            0,
+            &mut |dir_entry, entry| {
+                if entries.len() == 3 {
+                    return Ok(0);
+                }
+                entries.push((dir_entry.name.to_vec(), dir_entry.ino, entry));
+                Ok(1)
+            },
+        );
+        fs.unlink(&ctx, ROOT_INODE, c"file_0").unwrap();
+        fs.unlink(&ctx, ROOT_INODE, c"file_1").unwrap();
+        let getattr_listed = fs.getattr(&ctx, file_0, None);
+        let getattr_unlisted = fs.getattr(&ctx, file_1, None);
+        fs.forget(&ctx, file_0, 1);
+        let getattr_forgotten = fs.getattr(&ctx, file_0, None);
+
+        // Assert
+
+        readdirplus.unwrap();
+        let names: Vec<_> = entries.iter().map(|(name, ..)| name.as_slice()).collect();
+        assert_eq!(names, [&b"."[..], b"..", b"file_0"]);
+        assert_eq!(entries[0].2.inode, 0);
+        assert_eq!(entries[1].2.inode, 0);
+        let (_, ino, entry) = &entries[2];
+        assert_eq!(*ino, file_0);
+        assert_eq!(entry.inode, file_0);
+        assert_eq!(entry.attr.st_mode, libc::S_IFREG | 0o640);
+        assert_eq!(getattr_listed.unwrap().0.st_ino, file_0);
+        assert_eq!(
+            getattr_unlisted.unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            getattr_forgotten.unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+    }
+
+    #[test_log::test]
+    fn readdirplus_keeps_entries_before_an_error() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let file = fs
+            .mknod(&ctx, ROOT_INODE, c"file", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap()
+            .inode;
+        fs.forget(&ctx, file, 1);
+        fs.mknod(&ctx, ROOT_INODE, c"other", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let (handle, _) = fs.opendir(&ctx, ROOT_INODE, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut names = Vec::new();
+
+        // Act
+
+        // Adding the entry after "file" fails
+        let readdirplus = fs.readdirplus(&ctx, ROOT_INODE, handle, 4096, 0, &mut |dir_entry, _| {
+            if names.len() == 3 {
+                return Err(io::Error::other("reply is broken"));
+            }
+            names.push(dir_entry.name.to_vec());
+            Ok(1)
+        });
+        let failing = fs.readdirplus(&ctx, ROOT_INODE, handle, 4096, 3, &mut |_, _| {
+            Err(io::Error::other("reply is broken"))
+        });
+        fs.unlink(&ctx, ROOT_INODE, c"file").unwrap();
+        let getattr_listed = fs.getattr(&ctx, file, None);
+
+        // Assert
+
+        readdirplus.unwrap();
+        assert_eq!(names, [&b"."[..], b"..", b"file"]);
+        failing.unwrap_err();
+        // The lookup of the listed file keeps it alive until the kernel forgets it
+        assert_eq!(getattr_listed.unwrap().0.st_ino, file);
+    }
+
+    #[test_log::test]
+    fn cache_timeouts() {
+        // Arrange
+        let timeouts = CacheTimeouts {
+            attr: Duration::from_millis(1500),
+            entry: Duration::from_secs(7),
+            negative: Duration::from_secs(3),
+        };
+        let fs = MyFileSystem::new(&MountOptions {
+            timeouts,
+            ..MountOptions::default()
+        });
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+
+        // Act
+
+        let mkdir = fs.mkdir(&ctx, ROOT_INODE, c"folder", 0o755, 0).unwrap();
+        let lookup = fs.lookup(&ctx, ROOT_INODE, c"folder").unwrap();
+        let (_, getattr_timeout) = fs.getattr(&ctx, mkdir.inode, None).unwrap();
+        // SAFETY: stat64 only consists of integers, for which zero is a valid value
+        let attr: stat64 = unsafe { std::mem::zeroed() };
+        let (_, setattr_timeout) = fs
+            .setattr(&ctx, mkdir.inode, attr, None, SetattrValid::empty())
+            .unwrap();
+
+        // Assert
+
+        for entry in [mkdir, lookup] {
+            assert_eq!(entry.attr_timeout, timeouts.attr);
+            assert_eq!(entry.entry_timeout, timeouts.entry);
+        }
+        assert_eq!(getattr_timeout, timeouts.attr);
+        assert_eq!(setattr_timeout, timeouts.attr);
+    }
+
+    #[test_log::test]
+    fn notifications() {
+        // Arrange
+        let (sender, messages) = mpsc::channel();
+        let notifier = Notifier::new(Messages(sender));
+
+        // Act
+
+        notifier.invalidate_entry(ROOT_INODE, OsStr::new("name"));
+        let entry_message = messages.recv().unwrap();
+
+        // Assert
+
+        // The header holds the length, the opcode in place of the error and 0 as unique
+        let header = |len: u32, opcode: i32| {
+            [&len.to_ne_bytes()[..], &opcode.to_ne_bytes(), &[0; 8]].concat()
+        };
+        let entry_body = [
+            &ROOT_INODE.to_ne_bytes()[..],
+            &4u32.to_ne_bytes(),
+            &[0; 4],
+            b"name\0",
+        ]
+        .concat();
+        assert_eq!(entry_message, [header(37, 3), entry_body].concat());
+    }
+
+    #[test_log::test]
+    fn negative_lookups() {
+        // Arrange
+        let options = |negative| MountOptions {
+            timeouts: CacheTimeouts {
+                negative,
+                ..CacheTimeouts::default()
+            },
+            ..MountOptions::default()
+        };
+        let mut fs = MyFileSystem::new(&options(Duration::from_secs(5)));
+        let (sender, messages) = mpsc::channel();
+        fs.notifier = Some(Notifier::new(Messages(sender)));
+        fs.init(FsOptions::empty()).unwrap();
+        let uncached = MyFileSystem::new(&options(Duration::ZERO));
+        uncached.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let errno = |error: io::Error| error.raw_os_error();
+
+        // Act
+
+        let missing = fs.lookup(&ctx, ROOT_INODE, c"a");
+        let uncached_missing = uncached.lookup(&ctx, ROOT_INODE, c"a");
+        let file = fs
+            .mknod(&ctx, ROOT_INODE, c"a", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let below_file = fs.lookup(&ctx, file.inode, c"a");
+        fs.mkdir(&ctx, ROOT_INODE, c"b", 0o755, 0).unwrap();
+        fs.rename(&ctx, ROOT_INODE, c"a", ROOT_INODE, c"c", 0)
+            .unwrap();
+        // Replacing an existing name leaves no miss behind
+        fs.symlink(&ctx, c"c", ROOT_INODE, c"d").unwrap();
+        fs.rename(&ctx, ROOT_INODE, c"d", ROOT_INODE, c"c", 0)
+            .unwrap();
+        fs.create(
+            &ctx,
+            ROOT_INODE,
+            c"e",
+            CreateIn {
+                flags: libc::O_WRONLY as u32,
+                mode: 0o644,
+                umask: 0,
+                fuse_flags: 0,
+            },
+        )
+        .unwrap();
+        // The name of an invalidated entry follows the header and the parent, name length and padding
+        let names: Vec<_> = messages
+            .iter()
+            .map(|message| String::from_utf8(message[32..message.len() - 1].to_vec()).unwrap())
+            .take_while_inclusive(|name| name != "e")
+            .collect();
+
+        // Assert
+
+        let missing = missing.unwrap();
+        assert_eq!(missing.inode, 0);
+        assert_eq!(missing.entry_timeout, Duration::from_secs(5));
+        assert_eq!(errno(uncached_missing.unwrap_err()), Some(libc::ENOENT));
+        assert_eq!(errno(below_file.unwrap_err()), Some(libc::ENOTDIR));
+        assert_eq!(names, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test_log::test]
+    fn negative_lookups_mounted() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            timeouts: CacheTimeouts {
+                negative: Duration::from_secs(60),
+                ..CacheTimeouts::default()
+            },
+            ..MountOptions::default()
+        });
+        let path = fixture.path().join("probe");
+
+        // Act
+
+        let before = fs::metadata(&path);
+        fs::write(&path, "found").unwrap();
+        let after = fs::read(&path);
+
+        // Assert
+
+        assert_eq!(before.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(after.unwrap(), b"found");
+    }
+
+    #[test_log::test]
+    fn readdir_while_changing() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            threads: std::num::NonZeroUsize::new(4),
+            ..Default::default()
+        });
+        let path = fixture.path();
+        // The names are long, so a listing takes many requests
+        let stable: Vec<_> = (0..300)
+            .map(|i| format!("stable_file_with_a_rather_long_name_{i:03}"))
+            .collect();
+        for name in &stable {
+            fs::write(path.join(name), "").unwrap();
+        }
+
+        // Act
+
+        let listings: Vec<Vec<String>> = thread::scope(|scope| {
+            let changer = scope.spawn(|| {
+                for i in 0..300 {
+                    let name = path.join(format!("changing_file_with_a_long_name_{i:03}"));
+                    fs::write(&name, "").unwrap();
+                    if i % 2 == 0 {
+                        fs::remove_file(&name).unwrap();
+                    }
+                }
+            });
+            let mut listings = Vec::new();
+            while !changer.is_finished() || listings.is_empty() {
+                let listing = fs::read_dir(path)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .filter(|name| name.starts_with("stable"))
+                    .sorted()
+                    .collect();
+                listings.push(listing);
+            }
+            listings
+        });
+
+        // Assert
+
+        for listing in listings {
+            assert_eq!(listing, stable);
+        }
+    }
+
+    #[test_log::test]
+    fn concurrent_appends() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            threads: std::num::NonZeroUsize::new(4),
+            ..Default::default()
+        });
+        let path = fixture.path().join("log");
+
+        // Act
+
+        thread::scope(|scope| {
+            for i in 0..4 {
+                let path = &path;
+                scope.spawn(move || {
+                    let mut file = fs::File::options()
+                        .append(true)
+                        .create(true)
+                        .open(path)
+                        .unwrap();
+                    for j in 0..100 {
+                        file.write_all(format!("{i}:{j:03}\n").as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+
+        // Assert
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = content.lines().sorted().collect();
+        let expected: Vec<_> = (0..4)
+            .flat_map(|i| (0..100).map(move |j| format!("{i}:{j:03}")))
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test_log::test]
+    fn mknod_special_files() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let fifo = CString::new(fixture.path().join("fifo").as_os_str().as_bytes()).unwrap();
+
+        // Act
+
+        let result = unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) };
+
+        // Assert
+
+        assert_eq!(result, 0);
+        let metadata = fs::symlink_metadata(fixture.path().join("fifo")).unwrap();
+        assert!(metadata.file_type().is_fifo());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+        let entry = fs::read_dir(fixture.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert!(entry.file_type().unwrap().is_fifo());
+    }
+
+    #[test_log::test]
+    fn non_utf8_names() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let latin1 = fixture.path().join(OsStr::from_bytes(b"caf\xe9"));
+        let renamed = fixture.path().join(OsStr::from_bytes(b"\xe9t\xe9"));
+
+        // Act
+
+        fs::write(&latin1, "test").unwrap();
+        fs::rename(&latin1, &renamed).unwrap();
+
+        // Assert
+
+        let names: Vec<_> = fs::read_dir(fixture.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, [OsStr::from_bytes(b"\xe9t\xe9")]);
+        assert_eq!(fs::read_to_string(&renamed).unwrap(), "test");
+        fs::remove_file(&renamed).unwrap();
+        assert!(!renamed.exists());
+    }
+
+    #[test_log::test]
+    fn invalid_names() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let longest = CString::new(vec![b'a'; 255]).unwrap();
+        let too_long = CString::new(vec![b'a'; 256]).unwrap();
+
+        // Act
+
+        let create_longest = fs.mknod(&ctx, ROOT_INODE, &longest, libc::S_IFREG | 0o644, 0, 0);
+        let create_too_long = fs.mknod(&ctx, ROOT_INODE, &too_long, libc::S_IFREG | 0o644, 0, 0);
+        let lookup_too_long = fs.lookup(&ctx, ROOT_INODE, &too_long);
+        let mkdir_separator = fs.mkdir(&ctx, ROOT_INODE, c"a/b", 0o755, 0);
+
+        // Assert
+
+        let errno = |error: io::Error| error.raw_os_error();
+        assert!(create_longest.is_ok());
+        assert_eq!(
+            errno(create_too_long.unwrap_err()),
+            Some(libc::ENAMETOOLONG)
+        );
+        assert_eq!(
+            errno(lookup_too_long.unwrap_err()),
+            Some(libc::ENAMETOOLONG)
+        );
+        assert_eq!(errno(mkdir_separator.unwrap_err()), Some(libc::EINVAL));
+    }
+
+    #[test_log::test]
+    fn unlink_open_file() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        let mut file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"test").unwrap();
+
+        // Act
+
+        fs::remove_file(&path).unwrap();
+        file.write_all(b" data").unwrap();
+
+        // Assert
+
+        assert!(!path.exists());
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content.as_str(), "test data");
+        assert_eq!(file.metadata().unwrap().nlink(), 0);
+    }
+
+    #[test_log::test]
+    fn unlinked_open_file_keeps_inode() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+        let file = fs::File::open(&path).unwrap();
+        let inode = file.metadata().unwrap().ino();
+
+        // Act
+
+        fs::remove_file(&path).unwrap();
+        fs::write(fixture.path().join("other"), "other").unwrap();
+
+        // Assert
+
+        let other = fs::metadata(fixture.path().join("other")).unwrap();
+        assert_ne!(other.ino(), inode);
+        assert_eq!(file.metadata().unwrap().ino(), inode);
+    }
+
+    #[test_log::test]
+    fn unlinked_open_file_can_not_be_linked() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let file = fs
+            .mknod(&ctx, ROOT_INODE, c"test", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        // The open handle keeps the node alive after its last entry is removed
+        fs.open(&ctx, file.inode, libc::O_RDWR as u32, 0).unwrap();
+        fs.unlink(&ctx, ROOT_INODE, c"test").unwrap();
+
+        // Act
+
+        let link = fs.link(&ctx, file.inode, ROOT_INODE, c"link");
+
+        // Assert
+
+        assert_eq!(link.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        let lookup = fs.lookup(&ctx, ROOT_INODE, c"link");
+        assert_eq!(lookup.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test_log::test]
+    fn owner_of_new_file() {
+        // Arrange
+        let fixture = TestFixture::new();
+
+        // Act
+
+        fs::write(fixture.path().join("test"), "test").unwrap();
+        fs::DirBuilder::new()
+            .mode(0o750)
+            .create(fixture.path().join("folder"))
+            .unwrap();
+
+        // Assert
+
+        let metadata = fs::metadata(fixture.path().join("test")).unwrap();
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        assert_eq!(metadata.uid(), uid);
+        assert_eq!(metadata.gid(), gid);
+
+        let metadata = fs::metadata(fixture.path().join("folder")).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o750);
+    }
+
+    #[test_log::test]
+    fn chmod_and_chown() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+
+        // Act
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        unix::fs::chown(&path, Some(1234), Some(5678)).unwrap();
+
+        // Assert
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+        assert_eq!(metadata.uid(), 1234);
+        assert_eq!(metadata.gid(), 5678);
+        assert_eq!(fs::read(&path).unwrap(), b"test");
+    }
+
+    #[test_log::test]
+    fn permissions_are_checked_for_other_users() {
+        // Switching to another user is only possible as root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            allow_other: true,
+            ..Default::default()
+        });
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let as_nobody = |script: &str| {
+            Command::new("sh")
+                .arg("-c")
+                .arg(script)
+                .arg("sh")
+                .arg(&path)
+                .uid(65534)
+                .gid(65534)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .unwrap()
+        };
+
+        // Act
+
+        let read = as_nobody("cat \"$1\"");
+        let write = as_nobody("echo denied > \"$1\"");
+
+        // Assert
+
+        assert!(read.success());
+        assert!(!write.success());
+        assert_eq!(fs::read(&path).unwrap(), b"test");
+    }
+
+    #[test_log::test]
+    fn timestamps_are_stable() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+        let before = fs::metadata(&path).unwrap();
+
+        // Act
+
+        thread::sleep(Duration::from_millis(1100));
+        let after = fs::metadata(&path).unwrap();
+
+        // Assert
+
+        assert_eq!(before.modified().unwrap(), after.modified().unwrap());
+        assert_eq!(before.ctime(), after.ctime());
+    }
+
+    #[test_log::test]
+    fn write_updates_mtime() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+        let before = fs::metadata(&path).unwrap().modified().unwrap();
+        let folder_before = fs::metadata(fixture.path()).unwrap().modified().unwrap();
+
+        // Act
+
+        thread::sleep(Duration::from_millis(10));
+        fs::write(&path, "changed").unwrap();
+        fs::write(fixture.path().join("other"), "other").unwrap();
+
+        // Assert
+
+        let after = fs::metadata(&path).unwrap().modified().unwrap();
+        assert!(after > before);
+        let folder_after = fs::metadata(fixture.path()).unwrap().modified().unwrap();
+        assert!(folder_after > folder_before);
+    }
+
+    #[test_log::test]
+    fn set_times() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+        let accessed = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let modified = SystemTime::UNIX_EPOCH - Duration::from_nanos(1_500_000_000);
+
+        // Act
+
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_times(
+            fs::FileTimes::new()
+                .set_accessed(accessed)
+                .set_modified(modified),
+        )
+        .unwrap();
+
+        // Assert
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.accessed().unwrap(), accessed);
+        assert_eq!(metadata.modified().unwrap(), modified);
+    }
+
+    fn set_xattr(path: &Path, name: &str, value: &[u8], flags: i32) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                flags,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn get_xattr(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let mut buf = vec![0u8; 1024];
+        let result = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(result as usize);
+        Ok(buf)
+    }
+
+    fn rename_with_flags(from: &Path, to: &Path, flags: u32) -> io::Result<()> {
+        let from = CString::new(from.as_os_str().as_bytes()).unwrap();
+        let to = CString::new(to.as_os_str().as_bytes()).unwrap();
+        let result = unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                from.as_ptr(),
+                libc::AT_FDCWD,
+                to.as_ptr(),
+                flags,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn list_xattr(path: &Path) -> io::Result<Vec<u8>> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut buf = vec![0u8; 1024];
+        let result = unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(result as usize);
+        Ok(buf)
+    }
+
+    #[test_log::test]
+    fn xattr() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+
+        // Act
+
+        set_xattr(&path, "user.hash", b"1234", 0).unwrap();
+        set_xattr(&path, "user.other", b"value", 0).unwrap();
+        set_xattr(&path, "user.other", b"replaced", libc::XATTR_REPLACE).unwrap();
+
+        // Assert
+
+        assert_eq!(get_xattr(&path, "user.hash").unwrap(), b"1234");
+        assert_eq!(get_xattr(&path, "user.other").unwrap(), b"replaced");
+        assert_eq!(list_xattr(&path).unwrap(), b"user.hash\0user.other\0");
 
-impl MetricsHook for LoggingMetricsHook {
-    fn collect(&self, ih: &fuse_backend_rs::abi::fuse_abi::InHeader) {
-        trace!("Begin request {:?}", Opcode::from(ih.opcode));
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new("user.hash").unwrap();
+        assert_eq!(
+            unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) },
+            0
+        );
     }
 
-    fn release(&self, oh: Option<&fuse_backend_rs::abi::fuse_abi::OutHeader>) {
-        if let Some(e) = oh {
-            info!("{e:?}");
-        } else {
-            trace!("End request");
-        }
-    }
-}
+    #[test_log::test]
+    fn xattr_errors() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path().join("test");
+        fs::write(&path, "test").unwrap();
+        set_xattr(&path, "user.hash", b"1234", 0).unwrap();
 
-pub struct ServerSession<'a> {
-    server: Server<MyFileSystem<'a>>,
-    pub session: Arc<RwLock<FuseSession>>,
-    channel: FuseChannel,
-}
+        // Act
 
-impl ServerSession<'_> {
-    pub fn new(mount_point: &str) -> Self {
-        let filesystem = MyFileSystem::new();
-        let server = Server::new(filesystem);
-        let session = Arc::new(RwLock::new(
-            FuseSession::new(Path::new(mount_point), "my-fuse", "", false).unwrap(),
-        ));
+        let create = set_xattr(&path, "user.hash", b"5678", libc::XATTR_CREATE);
+        let replace = set_xattr(&path, "user.missing", b"5678", libc::XATTR_REPLACE);
+        let missing = get_xattr(&path, "user.missing");
+        let namespace = set_xattr(&path, "unknown.hash", b"5678", 0);
 
-        let channel = {
-            let mut session = session.write().unwrap();
-            session.set_allow_other(false);
-            session.mount().unwrap();
-            session.new_channel().unwrap()
-        };
+        // Assert
 
-        Self {
-            server,
-            session,
-            channel,
-        }
+        assert_eq!(create.unwrap_err().raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(replace.unwrap_err().raw_os_error(), Some(libc::ENODATA));
+        assert_eq!(missing.unwrap_err().raw_os_error(), Some(libc::ENODATA));
+        assert_eq!(
+            namespace.unwrap_err().raw_os_error(),
+            Some(libc::EOPNOTSUPP)
+        );
+        assert_eq!(get_xattr(&path, "user.hash").unwrap(), b"1234");
     }
 
-    pub fn start(&mut self) {
-        let metrics_hook = LoggingMetricsHook {};
+    #[test_log::test]
+    fn errno_through_mount() {
+        // Arrange
+        let fixture = TestFixture::new();
+        let path = fixture.path();
+        fs::write(path.join("file"), "test").unwrap();
+        fs::create_dir_all(path.join("folder/sub")).unwrap();
 
-        info!("Running fuse");
-        loop {
-            match self.channel.get_request() {
-                Ok(Some((reader, writer))) => {
-                    self.server
-                        .handle_message(reader, writer.into(), None, Some(&metrics_hook))
-                        .unwrap_or_else(|e| {
-                            error!("{e:?}");
-                            0
-                        });
-                }
-                Ok(None) => {
-                    info!("Cant handle message");
-                }
-                Err(e) => {
-                    error!("Request Error: {e}");
-                    break;
-                }
-            }
-        }
-    }
-}
+        // Act
 
-impl Drop for ServerSession<'_> {
-    fn drop(&mut self) {
-        info!("Unmounting");
-        {
-            let mut session = self.session.write().unwrap();
-            session.umount().unwrap();
-        }
+        let missing = fs::read(path.join("missing"));
+        let below_file = fs::read(path.join("file/child"));
+        let write_folder = fs::File::options().write(true).open(path.join("folder"));
+        let exists = fs::hard_link(path.join("file"), path.join("folder/sub"));
+        let not_empty = fs::remove_dir(path.join("folder"));
+        let too_large = fs::File::options()
+            .write(true)
+            .open(path.join("file"))
+            .unwrap()
+            .set_len(5 << 30);
+
+        // Assert
+
+        let errno = |result: io::Result<()>| result.unwrap_err().raw_os_error();
+        assert_eq!(errno(missing.map(|_| ())), Some(libc::ENOENT));
+        assert_eq!(errno(below_file.map(|_| ())), Some(libc::ENOTDIR));
+        assert_eq!(errno(write_folder.map(|_| ())), Some(libc::EISDIR));
+        assert_eq!(errno(exists), Some(libc::EEXIST));
+        assert_eq!(errno(not_empty), Some(libc::ENOTEMPTY));
+        assert_eq!(errno(too_large), Some(libc::EFBIG));
     }
-}
 
-pub mod test_util {
-    use crate::ServerSession;
-    use fuse_backend_rs::transport::FuseSession;
+    #[test_log::test]
+    fn errno_of_requests() {
+        // Arrange
+        let fs = MyFileSystem::new(&MountOptions::default());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let file = fs
+            .mknod(&ctx, ROOT_INODE, c"file", libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
 
-    use log::info;
-    use std::{
-        path::Path,
-        sync::{Arc, RwLock},
-        thread::{self, JoinHandle},
-    };
-    use tempdir::TempDir;
+        // Act
 
-    pub struct TestFixture {
-        session: Arc<RwLock<FuseSession>>,
-        thread: JoinHandle<()>,
-        tmp_dir: TempDir,
+        let lookup_below_file = fs.lookup(&ctx, file.inode, c"child");
+        let lookup_missing = fs.lookup(&ctx, ROOT_INODE, c"missing");
+        let opendir_file = fs.opendir(&ctx, file.inode, 0);
+        let release_unknown = fs.release(&ctx, file.inode, 0, 100, false, false, None);
+        let mknod_folder = fs.mknod(&ctx, ROOT_INODE, c"folder", libc::S_IFDIR, 0, 0);
+
+        // Assert
+
+        let errno = |error: io::Error| error.raw_os_error();
+        assert_eq!(errno(lookup_below_file.unwrap_err()), Some(libc::ENOTDIR));
+        assert_eq!(errno(lookup_missing.unwrap_err()), Some(libc::ENOENT));
+        assert_eq!(errno(opendir_file.unwrap_err()), Some(libc::ENOTDIR));
+        assert_eq!(errno(release_unknown.unwrap_err()), Some(libc::EBADF));
+        assert_eq!(errno(mknod_folder.unwrap_err()), Some(libc::EINVAL));
     }
 
-    impl Default for TestFixture {
-        fn default() -> Self {
-            Self::new()
-        }
+    #[test_log::test]
+    fn statfs() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            max_bytes: Some(1024 * 1024),
+            max_inodes: Some(100),
+            ..Default::default()
+        });
+        let path = CString::new(fixture.path().as_os_str().as_bytes()).unwrap();
+        let statvfs = || {
+            let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::statvfs(path.as_ptr(), &mut st) }, 0);
+            st
+        };
+        let before = statvfs();
+
+        // Act
+
+        fs::write(fixture.path().join("test"), vec![0u8; 64 * 1024]).unwrap();
+
+        // Assert
+
+        let after = statvfs();
+        assert_eq!(before.f_blocks * before.f_frsize, 1024 * 1024);
+        assert_eq!(before.f_files, 100);
+        assert_eq!(before.f_bfree - after.f_bfree, 16);
+        assert_eq!(before.f_ffree - after.f_ffree, 1);
     }
 
-    impl TestFixture {
-        pub fn new() -> Self {
-            let tmp_dir = TempDir::new("my-fuse").unwrap();
-            let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+    #[test_log::test]
+    fn write_beyond_capacity() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            max_bytes: Some(8192),
+            ..Default::default()
+        });
+        let path = fixture.path().join("test");
+        fs::write(&path, vec![0u8; 8000]).unwrap();
 
-            let mut server_session = ServerSession::new(tmp_dir_path.as_str());
+        // Act
 
-            let session = server_session.session.clone();
+        let write = fs::write(fixture.path().join("other"), vec![0u8; 100]);
+        let mut file = fs::File::options().write(true).open(&path).unwrap();
+        // Growing the file only adds a hole, but filling the hole needs space
+        let truncate = file.set_len(16384);
+        file.seek(SeekFrom::Start(12288)).unwrap();
+        let fill = file.write_all(b"test");
+        drop(file);
 
-            let thread = thread::spawn(move || {
-                server_session.start();
-            });
+        // Assert
 
-            Self {
-                session,
-                thread,
-                tmp_dir,
-            }
-        }
+        assert_eq!(write.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert!(truncate.is_ok());
+        assert_eq!(fill.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
 
-        pub fn path(&self) -> &Path {
-            self.tmp_dir.path()
-        }
+        fs::remove_file(&path).unwrap();
+        fs::write(fixture.path().join("other"), vec![0u8; 8192]).unwrap();
     }
 
-    impl Drop for TestFixture {
-        fn drop(&mut self) {
-            info!("Drop test fixture");
-            {
-                let mut session = self.session.write().unwrap();
-                session.umount().unwrap();
-            }
-            // TODO Lets join the thread
-        }
-    }
-}
+    #[test_log::test]
+    fn create_beyond_inode_capacity() {
+        // Arrange
+        let fixture = TestFixture::with_options(MountOptions {
+            max_inodes: Some(3),
+            ..Default::default()
+        });
+        fs::create_dir(fixture.path().join("folder")).unwrap();
+        fs::write(fixture.path().join("test"), "test").unwrap();
 
-#[cfg(test)]
-pub mod tests {
-    use crate::{ServerSession, test_util::TestFixture};
-    use fuse_backend_rs::transport::FuseSession;
+        // Act
 
-    use itertools::Itertools;
-    use log::info;
-    use std::{
-        fs,
-        path::Path,
-        sync::{Arc, RwLock},
-        thread::{self, JoinHandle},
-    };
-    use tempdir::TempDir;
+        let create = fs::write(fixture.path().join("other"), "test");
+        let mkdir = fs::create_dir(fixture.path().join("other_folder"));
+
+        // Assert
+
+        assert_eq!(create.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(mkdir.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+    }
 
     #[test_log::test]
-    fn mount_filesystem() {
+    fn restore_from_image() {
         // Arrange
-        let fixture = TestFixture::new();
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let options = MountOptions {
+            image: Some(image_dir.path().join("image")),
+            ..Default::default()
+        };
+        let fixture = TestFixture::with_options(options.clone());
+        fs::create_dir(fixture.path().join("folder")).unwrap();
+        fs::write(fixture.path().join("folder/test"), "test").unwrap();
+        fs::hard_link(
+            fixture.path().join("folder/test"),
+            fixture.path().join("link"),
+        )
+        .unwrap();
+        unix::fs::symlink("folder/test", fixture.path().join("symlink")).unwrap();
+        fs::set_permissions(
+            fixture.path().join("folder"),
+            fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+        set_xattr(&fixture.path().join("link"), "user.test", b"value", 0).unwrap();
+        let sparse = fs::File::create(fixture.path().join("sparse")).unwrap();
+        sparse.write_all_at(b"end", 1024 * 1024).unwrap();
+        drop(sparse);
+        let modified = fs::metadata(fixture.path().join("link"))
+            .unwrap()
+            .modified()
+            .unwrap();
 
         // Act
 
-        let dir_content = fs::read_dir(fixture.path()).unwrap();
+        drop(fixture);
+        let fixture = TestFixture::with_options(options);
 
         // Assert
-        assert_eq!(dir_content.count(), 0);
+
+        let path = fixture.path();
+        assert_eq!(
+            fs::read_to_string(path.join("folder/test")).unwrap(),
+            "test"
+        );
+        let link = fs::metadata(path.join("link")).unwrap();
+        assert_eq!(link.nlink(), 2);
+        assert_eq!(
+            link.ino(),
+            fs::metadata(path.join("folder/test")).unwrap().ino()
+        );
+        assert_eq!(link.modified().unwrap(), modified);
+        assert_eq!(
+            fs::read_link(path.join("symlink")).unwrap(),
+            Path::new("folder/test")
+        );
+        assert_eq!(
+            fs::metadata(path.join("folder")).unwrap().mode() & 0o7777,
+            0o700
+        );
+        assert_eq!(
+            get_xattr(&path.join("link"), "user.test").unwrap(),
+            b"value"
+        );
+        let sparse = fs::metadata(path.join("sparse")).unwrap();
+        assert_eq!(sparse.len(), 1024 * 1024 + 3);
+        assert_eq!(sparse.blocks(), 8);
+        // New nodes get inodes that are still free
+        fs::write(path.join("other"), "other").unwrap();
+        assert_ne!(fs::metadata(path.join("other")).unwrap().ino(), link.ino());
     }
 
     #[test_log::test]
-    fn write_file() {
+    fn broken_image_is_reported() {
         // Arrange
-        let fixture = TestFixture::new();
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        fs::write(&image, "not an image").unwrap();
+        let mount_point = tempdir::TempDir::new("my-fuse").unwrap();
 
         // Act
 
-        fs::write(fixture.path().join("test"), "test").unwrap();
+        let session = ServerSession::new(
+            mount_point.path().to_str().unwrap(),
+            MountOptions {
+                image: Some(image),
+                ..Default::default()
+            },
+        );
 
         // Assert
 
-        let dir_content = fs::read_dir(fixture.path()).unwrap();
-        assert_eq!(dir_content.count(), 1);
+        let error = session.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("Could not load image"));
     }
 
     #[test_log::test]
-    fn read_file() {
+    fn save_image_periodically() {
         // Arrange
-        let fixture = TestFixture::new();
-        fs::write(fixture.path().join("test"), "test").unwrap();
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        let fixture = TestFixture::with_options(MountOptions {
+            image: Some(image.clone()),
+            save_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
 
         // Act
 
-        let data = fs::read(fixture.path().join("test")).unwrap();
+        fs::write(fixture.path().join("test"), "test").unwrap();
+        thread::sleep(Duration::from_millis(200));
 
         // Assert
 
-        let dir_content = fs::read_dir(fixture.path()).unwrap();
-        assert_eq!(dir_content.count(), 1);
+        assert!(image.exists());
+        // The image is replaced atomically, so the temporary file never stays behind
+        drop(fixture);
+        assert!(!image_dir.path().join("image.tmp").exists());
+    }
 
-        let content = String::from_utf8(data).unwrap();
+    #[test_log::test]
+    fn replay_journal_after_crash() {
+        // Arrange
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        let options = MountOptions {
+            image: Some(image.clone()),
+            ..Default::default()
+        };
+        drop(TestFixture::with_options(options.clone()));
+        let fixture = TestFixture::with_options(options);
+        let path = fixture.path();
+        fs::create_dir(path.join("folder")).unwrap();
+        fs::write(path.join("test"), "test").unwrap();
+        fs::write(path.join("removed"), "removed").unwrap();
+        fs::rename(path.join("test"), path.join("folder/moved")).unwrap();
+        fs::hard_link(path.join("folder/moved"), path.join("link")).unwrap();
+        fs::remove_file(path.join("removed")).unwrap();
+        let mut file = fs::File::options()
+            .append(true)
+            .open(path.join("link"))
+            .unwrap();
+        file.write_all(b" data").unwrap();
+        file.set_len(7).unwrap();
+        drop(file);
+        fs::set_permissions(path.join("link"), fs::Permissions::from_mode(0o600)).unwrap();
+        set_xattr(&path.join("link"), "user.test", b"value", 0).unwrap();
 
-        assert_eq!(content.as_str(), "test");
+        // Act
+
+        // Copying the files while mounted is what a crash leaves behind
+        let crash_dir = tempdir::TempDir::new("my-fuse-crash").unwrap();
+        let crash_image = crash_dir.path().join("image");
+        fs::copy(&image, &crash_image).unwrap();
+        let journal = fs::read(image_dir.path().join("image.journal")).unwrap();
+        // The last operation was only partly written
+        let torn = [&journal[..], &[42, 0, 0, 0, 1, 2, 3]].concat();
+        fs::write(crash_dir.path().join("image.journal"), torn).unwrap();
+        drop(fixture);
+        let fixture = TestFixture::with_options(MountOptions {
+            image: Some(crash_image),
+            ..Default::default()
+        });
+
+        // Assert
+
+        let path = fixture.path();
+        assert_eq!(
+            fs::read_dir(path)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .sorted()
+                .collect_vec(),
+            ["folder", "link"]
+        );
+        assert_eq!(
+            fs::read_to_string(path.join("folder/moved")).unwrap(),
+            "test da"
+        );
+        let link = fs::metadata(path.join("link")).unwrap();
+        assert_eq!(link.nlink(), 2);
+        assert_eq!(link.mode() & 0o7777, 0o600);
+        assert_eq!(
+            get_xattr(&path.join("link"), "user.test").unwrap(),
+            b"value"
+        );
+        assert_eq!(fs::metadata(path).unwrap().nlink(), 3);
     }
 
     #[test_log::test]
-    fn mkdir() {
+    fn concurrent_requests() {
         // Arrange
-        let fixture = TestFixture::new();
+        let fixture = TestFixture::with_options(MountOptions {
+            threads: std::num::NonZeroUsize::new(4),
+            ..Default::default()
+        });
 
         // Act
 
-        fs::create_dir(fixture.path().join("test")).unwrap();
+        thread::scope(|scope| {
+            for i in 0..8 {
+                let folder = fixture.path().join(format!("folder_{i}"));
+                scope.spawn(move || {
+                    fs::create_dir(&folder).unwrap();
+                    for j in 0..50 {
+                        let path = folder.join(format!("file_{j}"));
+                        fs::write(&path, format!("{i} {j}")).unwrap();
+                        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{i} {j}"));
+                    }
+                });
+            }
+        });
 
         // Assert
 
-        let dir_content = fs::read_dir(fixture.path())
-            .unwrap()
-            .flat_map(|x| x.ok())
-            .collect_vec();
-        assert_eq!(dir_content.len(), 1);
+        assert_eq!(fs::read_dir(fixture.path()).unwrap().count(), 8);
+        for i in 0..8 {
+            let folder = fixture.path().join(format!("folder_{i}"));
+            assert_eq!(fs::read_dir(folder).unwrap().count(), 50);
+        }
+    }
 
-        let dir = dir_content.first().unwrap();
-        let filename = dir.file_name();
-        let name = filename.to_str().unwrap();
+    /// Hands the data of a write request to the filesystem like the kernel does
+    struct RequestData(io::Cursor<Vec<u8>>);
 
-        assert_eq!(name, "test");
+    impl Read for RequestData {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl ZeroCopyReader for RequestData {
+        fn read_to(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            let _ = (f, count, off);
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    /// Passes every write on as one message, like the FUSE device takes one notification per write
+    struct Messages(mpsc::Sender<Vec<u8>>);
+
+    impl Write for Messages {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf.to_vec()).unwrap();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Collects the reply of a read request
+    struct ReplyData(Vec<u8>);
+
+    impl Write for ReplyData {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for ReplyData {
+        fn write_from(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            let _ = (f, count, off);
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn available_bytes(&self) -> usize {
+            usize::MAX
+        }
+    }
+
+    /// A xorshift generator, so every run of the fuzz test sends the same requests
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn pick<T: Copy>(&mut self, values: &[T]) -> T {
+            values[self.below(values.len() as u64) as usize]
+        }
+
+        /// Mostly small numbers that hit existing nodes and offsets, sometimes extreme ones
+        fn number(&mut self) -> u64 {
+            match self.below(8) {
+                0 => self.next(),
+                1 => self.pick(&[
+                    0,
+                    u64::MAX,
+                    i64::MAX as u64,
+                    MAX_FILE_SIZE,
+                    MAX_FILE_SIZE - 1,
+                ]),
+                2 => self.below(1 << 20),
+                _ => self.below(24),
+            }
+        }
+    }
+
+    /// Sends `count` random requests to `fs` and returns the errors they failed with
+    fn fuzz(fs: &MyFileSystem, random: &mut Random, count: usize) -> Vec<io::Error> {
+        let ctx = Context::new();
+        let names: [&CStr; 6] = [c"a", c"b", c"c", c"\xff\xfe", c"user.a", c"security.b"];
+        let mut errors = Vec::new();
+        for _ in 0..count {
+            let inode = random.number();
+            let other = random.number();
+            let handle = random.number();
+            let offset = random.number();
+            let size = random.number() as u32;
+            let flags = random.number() as u32;
+            let (name, other_name) = (random.pick(&names), random.pick(&names));
+            let result = match random.below(29) {
+                0 => fs.lookup(&ctx, inode, name).map(drop),
+                1 => {
+                    fs.forget(&ctx, inode, offset);
+                    Ok(())
+                }
+                2 => fs.getattr(&ctx, inode, None).map(drop),
+                3 => {
+                    // SAFETY: stat64 only consists of integers, for which zero is a valid value
+                    let mut attr: stat64 = unsafe { std::mem::zeroed() };
+                    attr.st_size = offset as i64;
+                    attr.st_mtime = other as i64;
+                    let valid = SetattrValid::from_bits_truncate(flags);
+                    fs.setattr(&ctx, inode, attr, None, valid).map(drop)
+                }
+                4 => fs.mkdir(&ctx, inode, name, flags, 0).map(drop),
+                5 => fs.rmdir(&ctx, inode, name),
+                6 => fs.readdir(&ctx, inode, handle, size, offset, &mut |_| Ok(1)),
+                7 => fs.mknod(&ctx, inode, name, flags, size, 0).map(drop),
+                8 => fs.link(&ctx, inode, other, name).map(drop),
+                9 => fs.symlink(&ctx, other_name, inode, name).map(drop),
+                10 => fs.readlink(&ctx, inode).map(drop),
+                11 => fs.unlink(&ctx, inode, name),
+                12 => fs.rename(&ctx, inode, name, other, other_name, flags % 8),
+                13 => fs.open(&ctx, inode, flags, 0).map(drop),
+                14 => {
+                    let mut reply = ReplyData(Vec::new());
+                    let read = fs.read(
+                        &ctx,
+                        inode,
+                        handle,
+                        &mut reply,
+                        size % 65536,
+                        offset,
+                        None,
+                        0,
+                    );
+                    read.map(drop)
+                }
+                15 => {
+                    let data = vec![1; random.below(9000) as usize];
+                    let size = data.len() as u32;
+                    let mut request = RequestData(io::Cursor::new(data));
+                    fs.write(
+                        &ctx,
+                        inode,
+                        handle,
+                        &mut request,
+                        size,
+                        offset,
+                        None,
+                        false,
+                        0,
+                        0,
+                    )
+                    .map(drop)
+                }
+                16 => fs.statfs(&ctx, inode).map(drop),
+                17 => fs.release(&ctx, inode, 0, handle, false, false, None),
+                18 => fs.fallocate(&ctx, inode, handle, flags % 32, offset, other),
+                19 => fs.lseek(&ctx, inode, handle, offset, flags % 5).map(drop),
+                20 => fs.setxattr(&ctx, inode, name, &[0; 100], flags % 4),
+                21 => fs.getxattr(&ctx, inode, name, size).map(drop),
+                22 => fs.listxattr(&ctx, inode, size).map(drop),
+                23 => fs.opendir(&ctx, inode, flags).map(drop),
+                24 => fs.releasedir(&ctx, inode, flags, handle),
+                25 => fs.flush(&ctx, inode, handle, 0),
+                26 => fs.readdirplus(&ctx, inode, handle, size, offset, &mut |_, _| Ok(1)),
+                27 => {
+                    let args = CreateIn {
+                        flags,
+                        mode: size,
+                        umask: 0,
+                        fuse_flags: 0,
+                    };
+                    fs.create(&ctx, inode, name, args).map(drop)
+                }
+                _ => fs.removexattr(&ctx, inode, name),
+            };
+            errors.extend(result.err());
+        }
+        errors
+    }
+
+    /// Every path below the folder `inode` with what a restore has to bring back of its node
+    fn tree(fs: &MyFileSystem, inode: Inode, path: &Path, tree: &mut Vec<String>) {
+        let node = fs.load(inode).unwrap();
+        let node = node.read().unwrap();
+        let attr = node.get_entry(&fs.timeouts).attr;
+        tree.push(format!(
+            "{} {:o} {} {} {} {:?}",
+            path.display(),
+            attr.st_mode,
+            attr.st_size,
+            attr.st_blocks,
+            attr.st_nlink,
+            node.xattrs
+        ));
+        if let InnerNode::Folder(folder) = &node.inner {
+            for (name, child) in folder.iter() {
+                self::tree(fs, child, &path.join(name), tree);
+            }
+        }
     }
 
     #[test_log::test]
-    fn rmdir() {
+    fn fuzz_requests() {
         // Arrange
-        let fixture = TestFixture::new();
-        fs::create_dir(fixture.path().join("test")).unwrap();
+        let options = MountOptions {
+            max_bytes: Some(1024 * 1024),
+            max_inodes: Some(64),
+            ..Default::default()
+        };
+        let fs = MyFileSystem::new(&options);
+        fs.init(FsOptions::empty()).unwrap();
+        let image_dir = tempdir::TempDir::new("my-fuse-image").unwrap();
+        let image = image_dir.path().join("image");
+        let mut journaled = MyFileSystem::new(&options);
+        journaled.restore(&image, SyncPolicy::Never).unwrap();
+        journaled.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
 
         // Act
 
-        fs::remove_dir(fixture.path().join("test")).unwrap();
+        let mut errors = fuzz(&fs, &mut random, 50_000);
+        // The journal is started over by every save, the last part is only in the journal
+        for round in 0..3 {
+            if round > 0 {
+                crate::image::save(&journaled, &image).unwrap();
+            }
+            errors.extend(fuzz(&journaled, &mut random, 10_000));
+        }
+        let mut restored = MyFileSystem::new(&options);
+        let restore = restored.restore(&image, SyncPolicy::Never);
 
         // Assert
 
-        let dir_content = fs::read_dir(fixture.path())
-            .unwrap()
-            .flat_map(|x| x.ok())
-            .collect_vec();
-        assert_eq!(dir_content.len(), 0);
+        // Every failure reaches the kernel as a proper errno
+        for error in errors {
+            assert!(
+                error.raw_os_error().is_some_and(|errno| errno != libc::EIO),
+                "{error:?}"
+            );
+        }
+        fs.getattr(&ctx, ROOT_INODE, None).unwrap();
+        fs.statfs(&ctx, ROOT_INODE).unwrap();
+        restore.unwrap();
+        let (mut expected, mut actual) = (Vec::new(), Vec::new());
+        tree(&journaled, ROOT_INODE, Path::new("/"), &mut expected);
+        tree(&restored, ROOT_INODE, Path::new("/"), &mut actual);
+        assert_eq!(actual, expected);
     }
 }
@@ -1,6 +1,7 @@
 use clap::Parser;
-use log::info;
-use my_fuse::ServerSession;
+use log::{error, info};
+use my_fuse::{CacheTimeouts, MountOptions, ServerSession, SyncPolicy};
+use std::{num::NonZeroUsize, path::PathBuf, process, time::Duration};
 
 /// Custom FUSE filesystem
 #[derive(Parser, Debug)]
@@ -8,13 +9,108 @@ use my_fuse::ServerSession;
 struct Args {
     /// Path to the moint point of the filesystem. Example: /mnt
     mount_point: String,
+
+    /// Allow other users to access the filesystem. Access is checked against the file permissions
+    #[arg(long)]
+    allow_other: bool,
+
+    /// The most bytes all files together may take up. Accepts K, M and G suffixes. Example: 512M
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// The most files, folders and symlinks that may exist at the same time
+    #[arg(long)]
+    max_inodes: Option<u64>,
+
+    /// Load the filesystem from this image at startup and save it there at unmount
+    #[arg(long)]
+    image: Option<PathBuf>,
+
+    /// Also save the image every this many seconds while mounted. 0 only saves at unmount
+    #[arg(long, default_value_t = 60, requires = "image")]
+    save_interval: u64,
+
+    /// When changes in the journal of the image are synced to disk: always, never or every this many seconds
+    #[arg(long, default_value = "always", value_parser = parse_sync_policy, requires = "image")]
+    journal_sync: SyncPolicy,
+
+    /// The number of threads handling requests. Defaults to one per CPU
+    #[arg(long)]
+    threads: Option<NonZeroUsize>,
+
+    /// How many seconds the kernel may cache the attributes of files and folders. Example: 0.5
+    #[arg(long, default_value = "1", value_parser = parse_seconds)]
+    attr_timeout: Duration,
+
+    /// How many seconds the kernel may cache which file or folder a name belongs to
+    #[arg(long, default_value = "1", value_parser = parse_seconds)]
+    entry_timeout: Duration,
+
+    /// How many seconds the kernel may remember that a name does not exist. 0 disables it
+    #[arg(long, default_value = "0", value_parser = parse_seconds)]
+    negative_timeout: Duration,
+}
+
+/// Parses a byte count with an optional binary unit suffix like 4K, 512M or 2G
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, factor) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size {value}: {e}"))?
+        .checked_mul(factor)
+        .ok_or_else(|| format!("size {value} is too large"))
+}
+
+/// Parses a sync policy like always, never or 5
+fn parse_sync_policy(value: &str) -> Result<SyncPolicy, String> {
+    match value {
+        "always" => Ok(SyncPolicy::Always),
+        "never" => Ok(SyncPolicy::Never),
+        seconds => seconds
+            .parse()
+            .map(|seconds| SyncPolicy::Interval(Duration::from_secs(seconds)))
+            .map_err(|_| format!("invalid sync policy {value}: expected always, never or seconds")),
+    }
+}
+
+/// Parses a duration given in seconds with an optional fraction like 1 or 0.5
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let seconds = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid duration {value}: {e}"))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("invalid duration {value}: {e}"))
 }
 
 fn main() {
     let args = Args::parse();
     pretty_env_logger::init();
 
-    let mut server_session = ServerSession::new(args.mount_point.as_str());
+    let options = MountOptions {
+        allow_other: args.allow_other,
+        max_bytes: args.max_size,
+        max_inodes: args.max_inodes,
+        image: args.image,
+        save_interval: (args.save_interval > 0).then(|| Duration::from_secs(args.save_interval)),
+        journal_sync: args.journal_sync,
+        threads: args.threads,
+        timeouts: CacheTimeouts {
+            attr: args.attr_timeout,
+            entry: args.entry_timeout,
+            negative: args.negative_timeout,
+        },
+    };
+    let mut server_session = match ServerSession::new(args.mount_point.as_str(), options) {
+        Ok(server_session) => server_session,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
     {
         let session = server_session.session.clone();
 
@@ -22,7 +118,9 @@ fn main() {
         ctrlc::set_handler(move || {
             info!("Ctrl-C was pressed. Start unmounting");
             let mut session = session.write().unwrap();
-            session.umount().unwrap();
+            if let Err(e) = session.umount() {
+                error!("Could not unmount: {e}");
+            }
         })
         .expect("Error setting Ctrl-C handler");
     }
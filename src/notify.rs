@@ -0,0 +1,82 @@
+use std::{
+    ffi::{OsStr, OsString},
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    sync::mpsc,
+    thread,
+};
+
+use fuse_backend_rs::abi::fuse_abi::NotifyOpcode;
+use log::{debug, error};
+
+use crate::Inode;
+
+/// Something the kernel should drop from its caches
+#[derive(Debug)]
+enum Invalidation {
+    /// The entry with this name in the folder, which may also be a cached miss
+    Entry(Inode, OsString),
+}
+
+impl Invalidation {
+    /// The notification as it is written to the FUSE device
+    fn message(&self) -> Vec<u8> {
+        let Invalidation::Entry(parent, name) = self;
+        let mut body = parent.to_ne_bytes().to_vec();
+        body.extend((name.len() as u32).to_ne_bytes());
+        body.extend(0u32.to_ne_bytes());
+        body.extend(name.as_bytes());
+        body.push(0);
+        let opcode = NotifyOpcode::InvalEntry;
+        // Notifications have the header of a reply with the opcode as error and 0 as unique
+        let len = (HEADER_SIZE + body.len()) as u32;
+        let mut message = len.to_ne_bytes().to_vec();
+        message.extend((opcode as i32).to_ne_bytes());
+        message.extend(0u64.to_ne_bytes());
+        message.extend(body);
+        message
+    }
+}
+
+/// The size of the header in front of every notification
+const HEADER_SIZE: usize = 16;
+
+/// Tells the kernel to drop misses it cached for names that were created since.
+/// A background thread writes the notifications, because the kernel can hold the locks
+/// of the request that caused them until that request is answered.
+pub struct Notifier {
+    sender: mpsc::Sender<Invalidation>,
+}
+
+impl Notifier {
+    /// Starts the thread writing to `device`, which stops once the notifier is dropped
+    pub fn new(mut device: impl Write + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel::<Invalidation>();
+        thread::spawn(move || {
+            for invalidation in receiver {
+                // Every notification has to arrive in a single write
+                match device.write(&invalidation.message()) {
+                    Ok(_) => {}
+                    // The kernel has nothing cached for it or the filesystem is already unmounted
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::ENODEV)) => {
+                        debug!("Skipped {invalidation:?}: {e}")
+                    }
+                    Err(e) => error!("Could not send {invalidation:?}: {e}"),
+                }
+            }
+        });
+        Notifier { sender }
+    }
+
+    /// Drops the cached entry `name` in the folder `parent`
+    pub fn invalidate_entry(&self, parent: Inode, name: &OsStr) {
+        self.send(Invalidation::Entry(parent, name.to_owned()));
+    }
+
+    fn send(&self, invalidation: Invalidation) {
+        // The thread only stops before the notifier is dropped if it panicked
+        if self.sender.send(invalidation).is_err() {
+            error!("The notification thread stopped");
+        }
+    }
+}